@@ -0,0 +1,631 @@
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use secrecy::Secret;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::helpers::Cidr;
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Name the server gives itself in replies such as WHOIS and WHO
+    #[serde(default = "default_server_name")]
+    pub server_name: String,
+    /// Network named in the welcome message
+    #[serde(default = "default_network")]
+    pub network: String,
+    /// Shown in WHOIS next to the server name
+    #[serde(default = "default_description")]
+    pub description: String,
+    /// Contact details returned by ADMIN
+    #[serde(default)]
+    pub admin: AdminConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    /// Log filter used when `RUST_LOG` is unset; only read at startup
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default)]
+    pub history: HistoryConfig,
+    #[serde(default)]
+    pub accounts: AccountsConfig,
+    #[serde(default)]
+    pub channels: ChannelsConfig,
+    #[serde(default)]
+    pub bans: BansConfig,
+    #[serde(default = "default_listeners")]
+    pub listeners: Vec<ListenerConfig>,
+    /// Certificate for TLS listeners and STARTTLS
+    pub tls: Option<TlsConfig>,
+    /// Web gateways allowed to use WEBIRC
+    #[serde(default)]
+    pub gateways: Vec<GatewayConfig>,
+    /// Connection classes, tried in order; a connection matching none gets
+    /// the default limits
+    #[serde(default)]
+    pub classes: Vec<ClassConfig>,
+    /// Sets of privileges opers can be granted
+    #[serde(default)]
+    pub oper_classes: Vec<OperClassConfig>,
+    #[serde(default)]
+    pub opers: Vec<OperConfig>,
+    /// File sent to users as the message of the day
+    pub motd: Option<String>,
+    /// Configuration files read, in the order they were applied
+    #[serde(skip)]
+    pub files: Vec<PathBuf>,
+}
+
+impl ServerConfig {
+    /// The first class matching a connection from `ip` made through
+    /// `listener`.
+    pub fn class_for(&self, ip: &IpAddr, listener: &str) -> ClassConfig {
+        self.classes
+            .iter()
+            .find(|class| {
+                (class.ips.is_empty() || class.ips.iter().any(|cidr| cidr.contains(ip)))
+                    && (class.listeners.is_empty() || class.listeners.iter().any(|l| l == listener))
+            })
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Whether `ip` is allowed to connect as a WEBIRC gateway.
+    pub fn is_gateway(&self, ip: &IpAddr) -> bool {
+        self.gateways
+            .iter()
+            .any(|gateway| gateway.ips.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    /// Checks what deserializing alone cannot, so a bad config is turned
+    /// away before anything is started or replaced.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.server_name.is_empty() || self.server_name.contains([' ', ':']) {
+            return Err(format!(
+                "server_name: {:?} is not a valid server name",
+                self.server_name
+            ));
+        }
+        if let Err(e) = self.log_level.parse::<tracing_subscriber::EnvFilter>() {
+            return Err(format!("log_level: {}", e));
+        }
+        let mut bound = std::collections::HashSet::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if !listener.is_unix() && listener.port.is_none() {
+                return Err(format!("listeners[{}].port: TCP listeners need a port", i));
+            }
+            if listener.tls && self.tls.is_none() {
+                return Err(format!(
+                    "listeners[{}].tls: TLS listener configured without a certificate",
+                    i
+                ));
+            }
+            if !bound.insert(listener.bind_address()) {
+                return Err(format!(
+                    "listeners[{}].address: more than one listener on {}",
+                    i,
+                    listener.bind_address()
+                ));
+            }
+        }
+        for (i, class) in self.classes.iter().enumerate() {
+            if class.cidr_v4 > 32 {
+                return Err(format!("classes[{}].cidr_v4: must be at most 32", i));
+            }
+            if class.cidr_v6 > 128 {
+                return Err(format!("classes[{}].cidr_v6: must be at most 128", i));
+            }
+            //zero would ping, then time out, every client straight away
+            if class.ping_frequency == 0 {
+                return Err(format!("classes[{}].ping_frequency: must be positive", i));
+            }
+            if class.registration_timeout == 0 {
+                return Err(format!(
+                    "classes[{}].registration_timeout: must be positive",
+                    i
+                ));
+            }
+            if class.sendq == 0 {
+                return Err(format!("classes[{}].sendq: must be positive", i));
+            }
+            if class.flood_burst.is_nan() || class.flood_burst < 0.0 {
+                return Err(format!("classes[{}].flood_burst: must not be negative", i));
+            }
+            //a zero or NaN rate would leave the token bucket unable to refill
+            if class.flood_rate.is_nan() || class.flood_rate <= 0.0 {
+                return Err(format!("classes[{}].flood_rate: must be positive", i));
+            }
+            if class.recvq < 512 {
+                return Err(format!(
+                    "classes[{}].recvq: must hold at least one 512 byte line",
+                    i
+                ));
+            }
+        }
+        for (i, oper) in self.opers.iter().enumerate() {
+            if !self
+                .oper_classes
+                .iter()
+                .any(|class| class.name == oper.class)
+            {
+                return Err(format!(
+                    "opers[{}].class: unknown oper class {}",
+                    i, oper.class
+                ));
+            }
+            if oper.password.is_none() && oper.certfp.is_none() {
+                return Err(format!("opers[{}]: needs a password, a certfp or both", i));
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines of the message of the day; none when no MOTD file is set.
+    pub fn read_motd(&self) -> std::io::Result<Vec<String>> {
+        let Some(path) = &self.motd else {
+            return Ok(vec![]);
+        };
+        let motd = std::fs::read_to_string(path)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("MOTD {}: {}", path, e)))?;
+        Ok(motd.lines().map(str::to_string).collect())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ClassConfig {
+    pub name: String,
+    /// Addresses the class applies to; any address when empty
+    #[serde(default)]
+    pub ips: Vec<Cidr>,
+    /// Names of the listeners the class applies to; all of them when empty
+    #[serde(default)]
+    pub listeners: Vec<String>,
+    /// Connections the class holds at once
+    pub max_clients: Option<usize>,
+    /// Connections the class holds from a single address
+    pub max_per_ip: Option<usize>,
+    /// Connections the class holds from a single range, sized by
+    /// `cidr_v4` and `cidr_v6`
+    pub max_per_cidr: Option<usize>,
+    #[serde(default = "default_cidr_v4")]
+    pub cidr_v4: u8,
+    #[serde(default = "default_cidr_v6")]
+    pub cidr_v6: u8,
+    /// Seconds a client may be idle before it is sent a PING
+    #[serde(default = "default_ping_frequency")]
+    pub ping_frequency: u64,
+    /// Seconds a client has to finish registering
+    #[serde(default = "default_registration_timeout")]
+    pub registration_timeout: u64,
+    /// Bytes that may be queued for a client before it is disconnected
+    #[serde(default = "default_sendq")]
+    pub sendq: usize,
+    /// Bytes a client may send ahead of what flood control lets through
+    /// before it is disconnected
+    #[serde(default = "default_recvq")]
+    pub recvq: usize,
+    /// Commands a client may send in a burst
+    #[serde(default = "default_flood_burst")]
+    pub flood_burst: f64,
+    /// Commands per second a client may send once its burst is spent
+    #[serde(default = "default_flood_rate")]
+    pub flood_rate: f64,
+    /// Trusted clients, such as bots, that are not rate limited
+    #[serde(default)]
+    pub flood_exempt: bool,
+}
+
+impl Default for ClassConfig {
+    fn default() -> Self {
+        Self {
+            name: "default".to_string(),
+            ips: vec![],
+            listeners: vec![],
+            max_clients: None,
+            max_per_ip: None,
+            max_per_cidr: None,
+            cidr_v4: default_cidr_v4(),
+            cidr_v6: default_cidr_v6(),
+            ping_frequency: default_ping_frequency(),
+            registration_timeout: default_registration_timeout(),
+            sendq: default_sendq(),
+            recvq: default_recvq(),
+            flood_burst: default_flood_burst(),
+            flood_rate: default_flood_rate(),
+            flood_exempt: false,
+        }
+    }
+}
+
+fn default_server_name() -> String {
+    "irc.oxide.local".to_string()
+}
+
+fn default_network() -> String {
+    "OxideNet".to_string()
+}
+
+fn default_description() -> String {
+    "OxideIRC".to_string()
+}
+
+fn default_log_level() -> String {
+    "debug".to_string()
+}
+
+/// Contact details returned by ADMIN.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    pub location: String,
+    pub organisation: String,
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub nick_length: usize,
+    pub channel_length: usize,
+    /// Longer topics are cut short
+    pub topic_length: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        Self {
+            nick_length: 30,
+            channel_length: 50,
+            topic_length: 390,
+        }
+    }
+}
+
+fn default_sendq() -> usize {
+    1024 * 1024
+}
+
+fn default_cidr_v4() -> u8 {
+    24
+}
+
+fn default_cidr_v6() -> u8 {
+    64
+}
+
+fn default_ping_frequency() -> u64 {
+    120
+}
+
+fn default_registration_timeout() -> u64 {
+    60
+}
+
+fn default_recvq() -> usize {
+    8192
+}
+
+fn default_flood_burst() -> f64 {
+    10.0
+}
+
+fn default_flood_rate() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OperClassConfig {
+    pub name: String,
+    pub privileges: Vec<Privilege>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct OperConfig {
+    /// Name given to the OPER command
+    pub name: String,
+    /// Argon2 hash of the OPER password; the certificate alone is checked
+    /// when unset
+    pub password: Option<Secret<String>>,
+    /// `user@host` masks the oper may connect from, matched against both
+    /// the hostname and the IP address
+    pub hosts: Vec<String>,
+    /// TLS client certificate the oper must present, on top of the
+    /// password when both are set
+    pub certfp: Option<String>,
+    /// Name of the oper class granting the oper's privileges
+    pub class: String,
+}
+
+/// Something an oper may be allowed to do.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub enum Privilege {
+    Kill,
+    Kline,
+    Rehash,
+    Die,
+    /// Send WALLOPS to users with `+w`
+    Wallops,
+    /// Send GLOBOPS to other opers
+    Globops,
+    /// See details normally private to a user, such as their certificate
+    SeeSecret,
+}
+
+impl Privilege {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Privilege::Kill => "kill",
+            Privilege::Kline => "kline",
+            Privilege::Rehash => "rehash",
+            Privilege::Die => "die",
+            Privilege::Wallops => "wallops",
+            Privilege::Globops => "globops",
+            Privilege::SeeSecret => "see-secret",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct GatewayConfig {
+    /// Gateway name the WEBIRC command must give
+    pub name: String,
+    /// Argon2 hash of the WEBIRC password
+    pub password: Secret<String>,
+    /// Addresses the gateway connects from
+    pub ips: Vec<Cidr>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// IPv4 or IPv6 address, or the path of a Unix socket when it starts
+    /// with `/`
+    pub address: String,
+    /// Required for TCP listeners
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub tls: bool,
+    /// Expect a PROXY protocol header before the IRC stream
+    #[serde(default)]
+    pub proxy: bool,
+    /// Load balancers allowed to send PROXY headers
+    #[serde(default)]
+    pub trusted_proxies: Vec<Cidr>,
+    /// Name shown in WHOIS and STATS P
+    pub label: Option<String>,
+    /// Speak the IRCv3 WebSocket binding instead of raw lines
+    #[serde(default)]
+    pub websocket: bool,
+    /// Origins browsers may connect to the WebSocket from, as `*` globs;
+    /// any origin is accepted when empty
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+impl ListenerConfig {
+    pub fn is_unix(&self) -> bool {
+        self.address.starts_with('/')
+    }
+
+    /// The label, or where the listener is bound when it has none.
+    pub fn name(&self) -> String {
+        self.label.clone().unwrap_or_else(|| self.bind_address())
+    }
+
+    /// Where the listener is bound, which identifies it across rehashes.
+    pub fn bind_address(&self) -> String {
+        match self.port {
+            Some(port) if !self.is_unix() && self.address.contains(':') => {
+                format!("[{}]:{}", self.address, port)
+            }
+            Some(port) if !self.is_unix() => format!("{}:{}", self.address, port),
+            _ => self.address.clone(),
+        }
+    }
+}
+
+fn default_listeners() -> Vec<ListenerConfig> {
+    vec![ListenerConfig {
+        address: "127.0.0.1".to_string(),
+        port: Some(6667),
+        tls: false,
+        proxy: false,
+        trusted_proxies: vec![],
+        label: None,
+        websocket: false,
+        allowed_origins: vec![],
+    }]
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    /// Messages kept per channel or direct-message conversation
+    pub max_messages: usize,
+    /// Messages older than this are dropped; unlimited when unset
+    pub max_age_secs: Option<u64>,
+    /// Upper bound on the number of messages a single CHATHISTORY returns
+    pub query_limit: usize,
+    /// Append-only journal used to restore history after a restart
+    pub file: Option<String>,
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        Self {
+            max_messages: 1000,
+            max_age_secs: None,
+            query_limit: 100,
+            file: None,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccountsConfig {
+    /// JSON document holding registered accounts; kept in memory when unset
+    pub file: Option<String>,
+    pub min_password_length: usize,
+    /// Seconds a user gets to identify before losing a registered nick
+    pub enforce_grace_secs: u64,
+}
+
+impl Default for AccountsConfig {
+    fn default() -> Self {
+        Self {
+            file: None,
+            min_password_length: 8,
+            enforce_grace_secs: 30,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelsConfig {
+    /// JSON document holding channel registrations; kept in memory when unset
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct BansConfig {
+    /// JSON document holding K-, D- and G-lines; kept in memory when unset
+    pub file: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// PEM certificate chain, re-read on REHASH and SIGHUP
+    pub cert: String,
+    /// PEM private key matching `cert`
+    pub key: String,
+}
+
+/// Picks the overlay file read on top of `base`.
+const ENVIRONMENT_VARIABLE: &str = "OXIDE_ENVIRONMENT";
+
+/// The environment the server runs in, each with an optional overlay file
+/// in the configuration directory.
+pub enum Environment {
+    Local,
+    Production,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Local => "local",
+            Environment::Production => "production",
+        }
+    }
+}
+
+impl TryFrom<String> for Environment {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        match s.to_lowercase().as_str() {
+            "local" => Ok(Self::Local),
+            "production" => Ok(Self::Production),
+            other => Err(format!(
+                "{}: {} is not a supported environment, use either `local` or `production`",
+                ENVIRONMENT_VARIABLE, other
+            )),
+        }
+    }
+}
+
+/// Extensions a configuration file may have, tried in order.
+const EXTENSIONS: [&str; 4] = ["yaml", "yml", "toml", "json"];
+
+/// The first file in `directory` named `stem` with a known extension.
+fn find_file(directory: &Path, stem: &str) -> Option<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|extension| directory.join(format!("{}.{}", stem, extension)))
+        .find(|path| path.is_file())
+}
+
+/// Reads `base`, then the overlay for the environment named by
+/// `OXIDE_ENVIRONMENT` (`local` when unset), then `OXIDE_` variables, each
+/// overriding the one before.
+pub fn get_configuration() -> Result<ServerConfig, config::ConfigError> {
+    let base_path = std::env::current_dir().expect("Failed to determine the current directory");
+    let configuration_directory = base_path.join("configuration");
+    let environment: Environment = std::env::var(ENVIRONMENT_VARIABLE)
+        .unwrap_or_else(|_| "local".into())
+        .try_into()
+        .map_err(config::ConfigError::Message)?;
+
+    //OXIDE_SERVER_NAME sets server_name, OXIDE_ADMIN__EMAIL sets admin.email;
+    //the environment selector is not a setting, so it is left out
+    let variables = std::env::vars()
+        .filter(|(name, _)| name != ENVIRONMENT_VARIABLE)
+        .collect::<config::Map<_, _>>();
+    let files = ["base", environment.as_str()]
+        .into_iter()
+        .filter_map(|stem| find_file(&configuration_directory, stem))
+        .collect::<Vec<_>>();
+    let mut builder = config::Config::builder();
+    for file in &files {
+        builder = builder.add_source(config::File::from(file.as_path()));
+    }
+    let settings = builder
+        .add_source(
+            config::Environment::with_prefix("OXIDE")
+                .prefix_separator("_")
+                .separator("__")
+                .source(Some(variables)),
+        )
+        .build()?;
+
+    let mut config = settings
+        .clone()
+        .try_deserialize::<ServerConfig>()
+        .map_err(|e| locate_error(&settings, e))?;
+    config.files = files;
+    config.validate().map_err(config::ConfigError::Message)?;
+    Ok(config)
+}
+
+/// Missing fields are reported without saying where they are missing from,
+/// so the sections are tried one at a time to find the one at fault.
+fn locate_error(settings: &config::Config, error: config::ConfigError) -> config::ConfigError {
+    if !matches!(error, config::ConfigError::Message(_)) {
+        return error;
+    }
+    fn check<T: DeserializeOwned>(settings: &config::Config, key: &str) -> Option<String> {
+        match settings.get::<T>(key) {
+            Err(config::ConfigError::NotFound(_)) | Ok(_) => None,
+            Err(e) => Some(format!("{}: {}", key, e)),
+        }
+    }
+    fn check_list<T: DeserializeOwned>(settings: &config::Config, key: &str) -> Option<String> {
+        let len = settings.get_array(key).map_or(0, |items| items.len());
+        (0..len).find_map(|i| check::<T>(settings, &format!("{}[{}]", key, i)))
+    }
+
+    check::<AdminConfig>(settings, "admin")
+        .or_else(|| check::<LimitsConfig>(settings, "limits"))
+        .or_else(|| check::<HistoryConfig>(settings, "history"))
+        .or_else(|| check::<AccountsConfig>(settings, "accounts"))
+        .or_else(|| check::<ChannelsConfig>(settings, "channels"))
+        .or_else(|| check::<BansConfig>(settings, "bans"))
+        .or_else(|| check::<TlsConfig>(settings, "tls"))
+        .or_else(|| check_list::<ListenerConfig>(settings, "listeners"))
+        .or_else(|| check_list::<GatewayConfig>(settings, "gateways"))
+        .or_else(|| check_list::<ClassConfig>(settings, "classes"))
+        .or_else(|| check_list::<OperClassConfig>(settings, "oper_classes"))
+        .or_else(|| check_list::<OperConfig>(settings, "opers"))
+        .map_or(error, config::ConfigError::Message)
+}
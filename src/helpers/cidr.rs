@@ -0,0 +1,212 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+/// An address range such as `10.0.0.0/8` or `2001:db8::/32`. A bare address
+/// is a range holding only that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    /// The range of the given prefix length that `ip` falls in.
+    pub fn containing(ip: &IpAddr, prefix: u8) -> Self {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let prefix = prefix.min(32);
+                let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+                Self {
+                    network: IpAddr::V4(Ipv4Addr::from(u32::from(ip) & mask)),
+                    prefix,
+                }
+            }
+            IpAddr::V6(ip) => {
+                let prefix = prefix.min(128);
+                let mask = u128::MAX.checked_shl(128 - u32::from(prefix)).unwrap_or(0);
+                Self {
+                    network: IpAddr::V6(Ipv6Addr::from(u128::from(ip) & mask)),
+                    prefix,
+                }
+            }
+        }
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(&network.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = prefix as usize / 8;
+    let remaining_bits = prefix % 8;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+    if remaining_bits == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| format!("invalid address in {}", s))?
+            .to_canonical();
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length in {}", s))?,
+            None => max_prefix,
+        };
+        Ok(Self { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn cidr(s: &str) -> Cidr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_bare_addresses() {
+        assert_eq!(cidr("10.0.0.0/8").to_string(), "10.0.0.0/8");
+        assert_eq!(cidr("2001:db8::/32").to_string(), "2001:db8::/32");
+        assert_eq!(cidr("192.0.2.7").to_string(), "192.0.2.7/32");
+        assert_eq!(cidr("::1").to_string(), "::1/128");
+        assert_eq!(cidr("0.0.0.0/0").to_string(), "0.0.0.0/0");
+    }
+
+    #[test]
+    fn mapped_addresses_parse_as_ipv4() {
+        assert_eq!(cidr("::ffff:192.0.2.7").to_string(), "192.0.2.7/32");
+    }
+
+    #[test]
+    fn refuses_bad_addresses_and_prefixes() {
+        for bad in [
+            "",
+            "/8",
+            "10.0.0/8",
+            "example.com",
+            "10.0.0.0/33",
+            "10.0.0.0/-1",
+            "10.0.0.0/",
+            "10.0.0.0/x",
+            "::/129",
+            "*@10.0.0.0/8",
+        ] {
+            assert!(bad.parse::<Cidr>().is_err(), "{:?} parsed", bad);
+        }
+    }
+
+    #[test]
+    fn contains_addresses_within_the_prefix() {
+        let range = cidr("10.1.0.0/16");
+        assert!(range.contains(&ip("10.1.0.0")));
+        assert!(range.contains(&ip("10.1.255.255")));
+        assert!(!range.contains(&ip("10.2.0.0")));
+        assert!(!range.contains(&ip("9.1.0.0")));
+    }
+
+    #[test]
+    fn contains_with_prefixes_off_byte_boundaries() {
+        let range = cidr("192.0.2.0/22");
+        assert!(range.contains(&ip("192.0.3.255")));
+        assert!(range.contains(&ip("192.0.0.1")));
+        assert!(!range.contains(&ip("192.0.4.0")));
+        let range = cidr("2001:db8::/33");
+        assert!(range.contains(&ip("2001:db8:7fff::1")));
+        assert!(!range.contains(&ip("2001:db8:8000::1")));
+    }
+
+    #[test]
+    fn zero_and_full_prefixes() {
+        assert!(cidr("0.0.0.0/0").contains(&ip("255.255.255.255")));
+        assert!(cidr("::/0").contains(&ip("2001:db8::1")));
+        assert!(cidr("192.0.2.7/32").contains(&ip("192.0.2.7")));
+        assert!(!cidr("192.0.2.7/32").contains(&ip("192.0.2.8")));
+    }
+
+    #[test]
+    fn families_do_not_match_each_other() {
+        assert!(!cidr("0.0.0.0/0").contains(&ip("2001:db8::1")));
+        assert!(!cidr("::/0").contains(&ip("192.0.2.7")));
+    }
+
+    #[test]
+    fn mapped_clients_match_ipv4_ranges() {
+        assert!(cidr("192.0.2.0/24").contains(&ip("::ffff:192.0.2.7")));
+    }
+
+    #[test]
+    fn containing_masks_off_the_host_bits() {
+        assert_eq!(
+            Cidr::containing(&ip("192.0.2.77"), 24),
+            cidr("192.0.2.0/24")
+        );
+        assert_eq!(
+            Cidr::containing(&ip("2001:db8:1:2:3::4"), 64),
+            cidr("2001:db8:1:2::/64")
+        );
+        assert_eq!(Cidr::containing(&ip("192.0.2.77"), 0), cidr("0.0.0.0/0"));
+        assert_eq!(
+            Cidr::containing(&ip("::ffff:192.0.2.77"), 24),
+            cidr("192.0.2.0/24")
+        );
+    }
+
+    #[test]
+    fn containing_clamps_oversized_prefixes() {
+        assert_eq!(
+            Cidr::containing(&ip("192.0.2.77"), 64),
+            cidr("192.0.2.77/32")
+        );
+        assert_eq!(Cidr::containing(&ip("::1"), 200), cidr("::1/128"));
+    }
+}
@@ -0,0 +1,65 @@
+/// Case-insensitive IRC glob match, where `*` matches any run of characters
+/// and `?` matches exactly one.
+pub fn mask_matches(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.to_lowercase().chars().collect::<Vec<_>>();
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn literal_masks_match_case_insensitively() {
+        assert!(mask_matches("Nick!User@Host", "nick!user@host"));
+        assert!(!mask_matches("nick!user@host", "nick!user@host2"));
+        assert!(!mask_matches("nick", ""));
+    }
+
+    #[test]
+    fn star_matches_any_run_including_none() {
+        assert!(mask_matches("*", ""));
+        assert!(mask_matches("*!*@*", "a!b@c"));
+        assert!(mask_matches("*@*.example.com", "u@a.b.example.com"));
+        assert!(mask_matches("a*b*c", "abc"));
+        assert!(mask_matches("a*b*c", "axxbyyc"));
+        assert!(!mask_matches("a*b*c", "axxbyy"));
+        assert!(!mask_matches("*.example.com", "example.com"));
+    }
+
+    #[test]
+    fn question_mark_matches_exactly_one() {
+        assert!(mask_matches("n?ck", "nick"));
+        assert!(!mask_matches("n?ck", "nck"));
+        assert!(!mask_matches("n?ck", "niick"));
+        assert!(mask_matches("?*", "x"));
+        assert!(!mask_matches("?*", ""));
+    }
+
+    #[test]
+    fn stars_backtrack_after_a_partial_match() {
+        assert!(mask_matches("*ab", "aab"));
+        assert!(mask_matches("*aab", "aaab"));
+        assert!(mask_matches("*a*a*a", "aaa"));
+        assert!(!mask_matches("*a*a*a*a", "aaa"));
+    }
+}
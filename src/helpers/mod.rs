@@ -1,3 +1,11 @@
+mod cidr;
 mod logging;
+mod mask;
+mod password;
+mod snapshot;
 
+pub use cidr::*;
 pub use logging::*;
+pub use mask::*;
+pub use password::*;
+pub use snapshot::*;
@@ -0,0 +1,58 @@
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+/// Saves a store as a single JSON document from a task of its own, so the
+/// store's lock is not held, and no runtime thread is blocked, while the
+/// file is written. Each snapshot replaces the whole file; when several
+/// are waiting only the latest is written.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+    sender: mpsc::UnboundedSender<String>,
+}
+
+impl SnapshotWriter {
+    /// Starts the writer for `path`, naming the store as `what` in errors.
+    /// Must be called inside a runtime.
+    pub fn spawn(path: PathBuf, what: &'static str) -> Self {
+        let (sender, mut receiver) = mpsc::unbounded_channel::<String>();
+        tokio::spawn(async move {
+            while let Some(mut contents) = receiver.recv().await {
+                while let Ok(newer) = receiver.try_recv() {
+                    contents = newer;
+                }
+                let path = path.clone();
+                let result = tokio::task::spawn_blocking(move || write_file(&path, &contents))
+                    .await
+                    .map_err(std::io::Error::other)
+                    .and_then(|result| result);
+                if let Err(e) = result {
+                    tracing::error!("Failed to save {}: {}", what, e);
+                }
+            }
+        });
+        Self { sender }
+    }
+
+    /// Queues `value` to be written; call while holding the store so
+    /// snapshots are queued in the order they were taken.
+    pub fn save<T: Serialize + ?Sized>(&self, value: &T) {
+        match serde_json::to_string_pretty(value) {
+            Ok(contents) => {
+                let _ = self.sender.send(contents);
+            }
+            Err(e) => tracing::error!("Failed to serialize snapshot: {}", e),
+        }
+    }
+}
+
+/// Writes to a temporary file first so a crash never leaves half a document.
+fn write_file(path: &Path, contents: &str) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(&tmp, path)
+}
@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::client::{Capability, Client};
+use super::ircd::SharedServerState;
+use super::response::{ResponseCode, ResponseParams};
+use super::tags::MessageTags;
+use crate::configuration::AccountsConfig;
+use crate::helpers::{hash_password, verify_password, SnapshotWriter};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Account {
+    pub name: String,
+    pub password_hash: String,
+    pub email: Option<String>,
+    /// Nicks owned by the account, including its own name
+    pub nicks: Vec<String>,
+    /// Client certificate fingerprints accepted by SASL EXTERNAL
+    #[serde(default)]
+    pub certfps: Vec<String>,
+    pub registered_at: String,
+}
+
+#[derive(Debug)]
+pub enum AccountError {
+    AccountExists,
+    NoSuchAccount,
+    BadPassword,
+    WeakPassword,
+    BadAccountName,
+    NickOwned,
+    CertfpOwned,
+    Storage(String),
+}
+
+impl fmt::Display for AccountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccountError::AccountExists => write!(f, "Account already exists"),
+            AccountError::NoSuchAccount => write!(f, "No such account"),
+            AccountError::BadPassword => write!(f, "Invalid password"),
+            AccountError::WeakPassword => write!(f, "Password too weak"),
+            AccountError::BadAccountName => write!(f, "Invalid account name"),
+            AccountError::NickOwned => write!(f, "Nick is owned by another account"),
+            AccountError::CertfpOwned => {
+                write!(f, "Fingerprint is already registered to an account")
+            }
+            AccountError::Storage(e) => write!(f, "Account storage failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+/// Registered accounts keyed by lowercased name, persisted as a single JSON
+/// document that is rewritten after every change.
+#[derive(Debug)]
+pub struct AccountStore {
+    accounts: HashMap<String, Account>,
+    writer: Option<SnapshotWriter>,
+    min_password_length: usize,
+}
+
+impl AccountStore {
+    pub fn load(config: &AccountsConfig) -> std::io::Result<Self> {
+        let mut store = Self {
+            accounts: HashMap::new(),
+            writer: None,
+            min_password_length: config.min_password_length,
+        };
+
+        if let Some(path) = config.file.as_ref().map(PathBuf::from) {
+            if path.exists() {
+                let accounts: Vec<Account> =
+                    serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+                store.accounts = accounts
+                    .into_iter()
+                    .map(|account| (account.name.to_lowercase(), account))
+                    .collect();
+                tracing::info!(
+                    "Loaded {} accounts from {}",
+                    store.accounts.len(),
+                    path.display()
+                );
+            }
+            store.writer = Some(SnapshotWriter::spawn(path, "accounts"));
+        }
+
+        Ok(store)
+    }
+
+    pub fn set_limits(&mut self, config: &AccountsConfig) {
+        self.min_password_length = config.min_password_length;
+    }
+
+    fn save(&self) {
+        if let Some(writer) = &self.writer {
+            writer.save(&self.accounts.values().collect::<Vec<_>>());
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(&name.to_lowercase())
+    }
+
+    /// Name of the account owning `nick`, if any.
+    pub fn nick_owner(&self, nick: &str) -> Option<String> {
+        self.accounts
+            .values()
+            .find(|account| account.nicks.iter().any(|n| n.eq_ignore_ascii_case(nick)))
+            .map(|account| account.name.clone())
+    }
+
+    /// Name of the account that accepts the certificate fingerprint `certfp`.
+    pub fn certfp_owner(&self, certfp: &str) -> Option<String> {
+        self.accounts
+            .values()
+            .find(|account| {
+                account
+                    .certfps
+                    .iter()
+                    .any(|fp| fp.eq_ignore_ascii_case(certfp))
+            })
+            .map(|account| account.name.clone())
+    }
+
+    fn check_name(&self, name: &str) -> Result<(), AccountError> {
+        if name.is_empty() || name.starts_with('#') || name.contains(['*', '!', '@', ' ']) {
+            return Err(AccountError::BadAccountName);
+        }
+        if self.get(name).is_some() || self.nick_owner(name).is_some() {
+            return Err(AccountError::AccountExists);
+        }
+        Ok(())
+    }
+
+    fn check_password(&self, password: &str) -> Result<(), AccountError> {
+        if password.len() < self.min_password_length {
+            return Err(AccountError::WeakPassword);
+        }
+        Ok(())
+    }
+
+    /// Adds an account with an already hashed password; see [`register`].
+    pub fn register(
+        &mut self,
+        name: &str,
+        password_hash: String,
+        email: Option<String>,
+    ) -> Result<(), AccountError> {
+        //checked again, as the name may have been taken while hashing
+        self.check_name(name)?;
+        self.accounts.insert(
+            name.to_lowercase(),
+            Account {
+                name: name.to_string(),
+                password_hash,
+                email,
+                nicks: vec![name.to_string()],
+                certfps: vec![],
+                registered_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+            },
+        );
+        self.save();
+        Ok(())
+    }
+
+    /// The canonical account name and password hash, for [`authenticate`].
+    pub fn password_hash(&self, name: &str) -> Result<(String, String), AccountError> {
+        let account = self.get(name).ok_or(AccountError::NoSuchAccount)?;
+        Ok((account.name.clone(), account.password_hash.clone()))
+    }
+
+    pub fn group(&mut self, name: &str, nick: &str) -> Result<(), AccountError> {
+        match self.nick_owner(nick) {
+            Some(owner) if owner.eq_ignore_ascii_case(name) => return Ok(()),
+            Some(_) => return Err(AccountError::NickOwned),
+            None => {}
+        }
+        let account = self
+            .accounts
+            .get_mut(&name.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        account.nicks.push(nick.to_string());
+        self.save();
+        Ok(())
+    }
+
+    pub fn add_certfp(&mut self, name: &str, certfp: &str) -> Result<(), AccountError> {
+        match self.certfp_owner(certfp) {
+            Some(owner) if owner.eq_ignore_ascii_case(name) => return Ok(()),
+            Some(_) => return Err(AccountError::CertfpOwned),
+            None => {}
+        }
+        let account = self
+            .accounts
+            .get_mut(&name.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        account.certfps.push(certfp.to_ascii_lowercase());
+        self.save();
+        Ok(())
+    }
+
+    pub fn remove_certfp(&mut self, name: &str, certfp: &str) -> Result<(), AccountError> {
+        let account = self
+            .accounts
+            .get_mut(&name.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        account
+            .certfps
+            .retain(|fp| !fp.eq_ignore_ascii_case(certfp));
+        self.save();
+        Ok(())
+    }
+
+    pub fn drop_account(&mut self, name: &str) -> Result<(), AccountError> {
+        self.accounts
+            .remove(&name.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        self.save();
+        Ok(())
+    }
+
+    /// Replaces an account's password hash; see [`set_password`].
+    pub fn set_password_hash(
+        &mut self,
+        name: &str,
+        password_hash: String,
+    ) -> Result<(), AccountError> {
+        let account = self
+            .accounts
+            .get_mut(&name.to_lowercase())
+            .ok_or(AccountError::NoSuchAccount)?;
+        account.password_hash = password_hash;
+        self.save();
+        Ok(())
+    }
+}
+
+/// Registers an account, hashing the password without holding the store so
+/// other account lookups carry on meanwhile.
+pub async fn register(
+    server_state: &SharedServerState,
+    name: &str,
+    password: &str,
+    email: Option<String>,
+) -> Result<(), AccountError> {
+    {
+        let accounts = server_state.accounts.read().await;
+        accounts.check_name(name)?;
+        accounts.check_password(password)?;
+    }
+    let password_hash = hash_password(password)
+        .await
+        .map_err(|e| AccountError::Storage(e.to_string()))?;
+    server_state
+        .accounts
+        .write()
+        .await
+        .register(name, password_hash, email)
+}
+
+/// Checks a password, returning the canonical account name.
+pub async fn authenticate(
+    server_state: &SharedServerState,
+    name: &str,
+    password: &str,
+) -> Result<String, AccountError> {
+    let (name, password_hash) = server_state.accounts.read().await.password_hash(name)?;
+    if verify_password(password, &password_hash).await {
+        Ok(name)
+    } else {
+        Err(AccountError::BadPassword)
+    }
+}
+
+pub async fn set_password(
+    server_state: &SharedServerState,
+    name: &str,
+    password: &str,
+) -> Result<(), AccountError> {
+    server_state
+        .accounts
+        .read()
+        .await
+        .check_password(password)?;
+    let password_hash = hash_password(password)
+        .await
+        .map_err(|e| AccountError::Storage(e.to_string()))?;
+    server_state
+        .accounts
+        .write()
+        .await
+        .set_password_hash(name, password_hash)
+}
+
+/// Marks the session as logged into `account`, confirms it to the client
+/// and notifies channel peers that negotiated `account-notify`.
+pub async fn log_in(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    account: String,
+) {
+    let (nickname, mask) = {
+        let mut active_session = session.write().await;
+        tracing::info!(
+            "{} logged in as {}",
+            active_session.nick.as_ref().unwrap(),
+            account
+        );
+        let params = ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
+            .nick(active_session.mask())
+            .account(account.clone());
+        active_session.account = Some(account.clone());
+        let _ = active_session
+            .sender
+            .send(ResponseCode::RPL_LOGGEDIN.message(params));
+        (active_session.nick.clone().unwrap(), active_session.mask())
+    };
+    notify_account(server_state, &nickname, &mask, &account).await;
+}
+
+pub async fn log_out(session: &Arc<RwLock<Client>>, server_state: &SharedServerState) {
+    let (nickname, mask) = {
+        let mut active_session = session.write().await;
+        if active_session.account.take().is_none() {
+            return;
+        }
+        let params = ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
+            .nick(active_session.mask());
+        let _ = active_session
+            .sender
+            .send(ResponseCode::RPL_LOGGEDOUT.message(params));
+        (active_session.nick.clone().unwrap(), active_session.mask())
+    };
+    notify_account(server_state, &nickname, &mask, "*").await;
+}
+
+async fn notify_account(
+    server_state: &SharedServerState,
+    nickname: &str,
+    mask: &str,
+    account: &str,
+) {
+    let formatted_message = format!(":{} ACCOUNT {}\r\n", mask, account);
+    let tags = MessageTags::new();
+    for handle in server_state.channel_peers(nickname).await {
+        let client = handle.read().await;
+        if client.capabilities.contains(&Capability::AccountNotify) {
+            client.send_tagged(&tags, &formatted_message);
+        }
+    }
+}
@@ -0,0 +1,341 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+use super::client::Client;
+use super::ircd::SharedServerState;
+use super::response::{ResponseCode, ResponseParams};
+use super::sendq::CloseReason;
+use super::snomask::Snomask;
+use crate::configuration::BansConfig;
+use crate::helpers::{mask_matches, Cidr, SnapshotWriter};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BanKind {
+    /// Bans a `user@host` mask from this server
+    Kline,
+    /// Bans an address or range, refused as soon as it connects
+    Dline,
+    /// Bans a `user@host` mask from the whole network. The server does not
+    /// link to others, so there is nothing to propagate it to and it acts
+    /// exactly as a K-line; it is only listed separately.
+    Gline,
+}
+
+impl BanKind {
+    pub fn letter(&self) -> char {
+        match self {
+            BanKind::Kline => 'K',
+            BanKind::Dline => 'D',
+            BanKind::Gline => 'G',
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServerBan {
+    pub kind: BanKind,
+    /// `user@host` for K- and G-lines, an address or CIDR range for D-lines
+    pub mask: String,
+    pub reason: String,
+    pub set_by: String,
+    pub set_at: i64,
+    /// Unix time the ban lapses; permanent when unset
+    pub expires_at: Option<i64>,
+}
+
+impl ServerBan {
+    fn is_active(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+    }
+
+    /// Whether the ban would cover every client, as `*@*` or `0.0.0.0/0`
+    /// would.
+    pub fn matches_everyone(&self) -> bool {
+        let covers_all_addresses = |mask: &str| {
+            mask.parse::<Cidr>().is_ok_and(|cidr| {
+                let v4 = [IpAddr::from([0u8; 4]), IpAddr::from([255u8; 4])];
+                let v6 = [IpAddr::from([0u8; 16]), IpAddr::from([255u8; 16])];
+                v4.iter().all(|ip| cidr.contains(ip)) || v6.iter().all(|ip| cidr.contains(ip))
+            })
+        };
+        //a mask of nothing but wildcards matches any name
+        let covers_all_names =
+            |mask: &str| mask.contains('*') && mask.chars().all(|c| c == '*' || c == '?');
+        match self.kind {
+            BanKind::Dline => covers_all_addresses(&self.mask),
+            BanKind::Kline | BanKind::Gline => {
+                self.mask
+                    .split_once('@')
+                    .is_some_and(|(user_mask, host_mask)| {
+                        covers_all_names(user_mask)
+                            && (covers_all_names(host_mask) || covers_all_addresses(host_mask))
+                    })
+            }
+        }
+    }
+
+    fn matches_address(&self, ip: &IpAddr) -> bool {
+        self.mask
+            .parse::<Cidr>()
+            .is_ok_and(|cidr| cidr.contains(ip))
+    }
+
+    pub fn matches(&self, user: &str, host: &str, ip: &IpAddr) -> bool {
+        match self.kind {
+            BanKind::Dline => self.matches_address(ip),
+            BanKind::Kline | BanKind::Gline => {
+                let Some((user_mask, host_mask)) = self.mask.split_once('@') else {
+                    return false;
+                };
+                mask_matches(user_mask, user)
+                    && (mask_matches(host_mask, host)
+                        || mask_matches(host_mask, &ip.to_string())
+                        || host_mask
+                            .parse::<Cidr>()
+                            .is_ok_and(|cidr| cidr.contains(ip)))
+            }
+        }
+    }
+}
+
+/// K-, D- and G-lines, persisted the same way as the account store.
+/// Expired bans are ignored and dropped the next time the store is saved.
+#[derive(Debug)]
+pub struct BanStore {
+    bans: Vec<ServerBan>,
+    writer: Option<SnapshotWriter>,
+}
+
+impl BanStore {
+    pub fn load(config: &BansConfig) -> std::io::Result<Self> {
+        let mut store = Self {
+            bans: vec![],
+            writer: None,
+        };
+
+        if let Some(path) = config.file.as_ref().map(PathBuf::from) {
+            if path.exists() {
+                let now = Utc::now().timestamp();
+                let bans: Vec<ServerBan> = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+                store.bans = bans.into_iter().filter(|ban| ban.is_active(now)).collect();
+                tracing::info!(
+                    "Loaded {} server bans from {}",
+                    store.bans.len(),
+                    path.display()
+                );
+            }
+            store.writer = Some(SnapshotWriter::spawn(path, "server bans"));
+        }
+
+        Ok(store)
+    }
+
+    fn save(&mut self) {
+        let now = Utc::now().timestamp();
+        self.bans.retain(|ban| ban.is_active(now));
+        if let Some(writer) = &self.writer {
+            writer.save(&self.bans);
+        }
+    }
+
+    /// Adds a ban, replacing any earlier one of the same kind and mask.
+    pub fn add(&mut self, ban: ServerBan) {
+        self.bans.retain(|existing| {
+            existing.kind != ban.kind || !existing.mask.eq_ignore_ascii_case(&ban.mask)
+        });
+        self.bans.push(ban);
+        self.save();
+    }
+
+    /// Removes a ban, returning whether there was one to remove.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> bool {
+        let before = self.bans.len();
+        self.bans
+            .retain(|ban| ban.kind != kind || !ban.mask.eq_ignore_ascii_case(mask));
+        let removed = self.bans.len() != before;
+        if removed {
+            self.save();
+        }
+        removed
+    }
+
+    pub fn list(&self, kind: BanKind) -> Vec<&ServerBan> {
+        let now = Utc::now().timestamp();
+        self.bans
+            .iter()
+            .filter(|ban| ban.kind == kind && ban.is_active(now))
+            .collect()
+    }
+
+    /// The D-line refusing connections from `ip`, if any.
+    pub fn dline_for(&self, ip: &IpAddr) -> Option<&ServerBan> {
+        let now = Utc::now().timestamp();
+        self.bans
+            .iter()
+            .find(|ban| ban.kind == BanKind::Dline && ban.is_active(now) && ban.matches_address(ip))
+    }
+
+    /// The first ban of any kind covering a client.
+    pub fn ban_for(&self, user: &str, host: &str, ip: &IpAddr) -> Option<&ServerBan> {
+        let now = Utc::now().timestamp();
+        self.bans
+            .iter()
+            .find(|ban| ban.is_active(now) && ban.matches(user, host, ip))
+    }
+}
+
+/// Disconnects the client if a server ban covers it, returning whether it
+/// was banned.
+pub async fn drop_if_banned(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+) -> bool {
+    let active_session = session.read().await;
+    let user = active_session.user.clone().unwrap_or_default();
+    let bans = server_state.bans.read().await;
+    let Some(ban) = bans.ban_for(&user, &active_session.host, &active_session.ip) else {
+        return false;
+    };
+
+    let nickname = active_session.nick.clone().unwrap();
+    tracing::info!(
+        snomask = %Snomask::Ban,
+        "Dropping {} for {}-line on {}: {}",
+        nickname,
+        ban.kind.letter(),
+        ban.mask,
+        ban.reason
+    );
+    let _ = active_session
+        .sender
+        .send(ResponseCode::ERR_YOUREBANNEDCREEP.message(ResponseParams::new(nickname)));
+    active_session.sender.close_link(CloseReason {
+        error: format!(
+            "Closing Link: {} ({}-lined: {})",
+            active_session.host,
+            ban.kind.letter(),
+            ban.reason
+        ),
+        quit: format!("{}-lined", ban.kind.letter()),
+    });
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ban(kind: BanKind, mask: &str) -> ServerBan {
+        ServerBan {
+            kind,
+            mask: mask.to_string(),
+            reason: "test".to_string(),
+            set_by: "oper".to_string(),
+            set_at: 0,
+            expires_at: None,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn kline_matches_user_and_host_globs() {
+        let kline = ban(BanKind::Kline, "bad*@*.Example.com");
+        assert!(kline.matches("badguy", "shell.example.com", &ip("192.0.2.1")));
+        assert!(kline.matches("BAD", "a.b.EXAMPLE.COM", &ip("192.0.2.1")));
+        assert!(!kline.matches("good", "shell.example.com", &ip("192.0.2.1")));
+        assert!(!kline.matches("badguy", "example.com", &ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn kline_host_matches_the_address_or_a_range() {
+        let by_address = ban(BanKind::Kline, "*@192.0.2.*");
+        assert!(by_address.matches("u", "host.example.com", &ip("192.0.2.9")));
+        assert!(!by_address.matches("u", "host.example.com", &ip("192.0.3.9")));
+        let by_range = ban(BanKind::Gline, "*@2001:db8::/32");
+        assert!(by_range.matches("u", "host.example.com", &ip("2001:db8::9")));
+        assert!(!by_range.matches("u", "host.example.com", &ip("2001:db9::9")));
+    }
+
+    #[test]
+    fn kline_without_a_user_part_matches_nothing() {
+        let kline = ban(BanKind::Kline, "example.com");
+        assert!(!kline.matches("u", "example.com", &ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn dline_matches_only_by_address() {
+        let dline = ban(BanKind::Dline, "192.0.2.0/24");
+        assert!(dline.matches("u", "elsewhere.example.com", &ip("192.0.2.200")));
+        assert!(!dline.matches("u", "192.0.2.200", &ip("198.51.100.1")));
+        let unparsable = ban(BanKind::Dline, "*.example.com");
+        assert!(!unparsable.matches("u", "a.example.com", &ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn matches_everyone_catches_wildcards_and_zero_prefixes() {
+        for (kind, mask) in [
+            (BanKind::Kline, "*@*"),
+            (BanKind::Kline, "**@?*"),
+            (BanKind::Gline, "*@0.0.0.0/0"),
+            (BanKind::Kline, "*@::/0"),
+            (BanKind::Dline, "0.0.0.0/0"),
+            (BanKind::Dline, "::/0"),
+        ] {
+            assert!(ban(kind, mask).matches_everyone(), "{}", mask);
+        }
+    }
+
+    #[test]
+    fn matches_everyone_allows_narrower_masks() {
+        for (kind, mask) in [
+            (BanKind::Kline, "*@*.example.com"),
+            (BanKind::Kline, "bad@*"),
+            (BanKind::Kline, "*@0.0.0.0/1"),
+            (BanKind::Kline, "?@*"),
+            (BanKind::Dline, "0.0.0.0/1"),
+            (BanKind::Dline, "2001:db8::/32"),
+        ] {
+            assert!(!ban(kind, mask).matches_everyone(), "{}", mask);
+        }
+    }
+
+    #[test]
+    fn store_replaces_bans_with_the_same_mask() {
+        let mut store = BanStore::load(&BansConfig::default()).unwrap();
+        store.add(ban(BanKind::Kline, "*@bad.example.com"));
+        store.add(ban(BanKind::Kline, "*@BAD.example.com"));
+        store.add(ban(BanKind::Gline, "*@bad.example.com"));
+        assert_eq!(store.list(BanKind::Kline).len(), 1);
+        assert_eq!(store.list(BanKind::Gline).len(), 1);
+        assert!(store.remove(BanKind::Kline, "*@bad.EXAMPLE.com"));
+        assert!(!store.remove(BanKind::Kline, "*@bad.example.com"));
+        assert!(store.list(BanKind::Kline).is_empty());
+    }
+
+    #[test]
+    fn store_ignores_expired_bans() {
+        let mut store = BanStore::load(&BansConfig::default()).unwrap();
+        let now = Utc::now().timestamp();
+        let mut expired = ban(BanKind::Dline, "192.0.2.0/24");
+        expired.expires_at = Some(now - 1);
+        store.bans.push(expired);
+        let mut current = ban(BanKind::Kline, "*@*.example.com");
+        current.expires_at = Some(now + 60);
+        store.bans.push(current);
+
+        assert!(store.dline_for(&ip("192.0.2.1")).is_none());
+        assert!(store.ban_for("u", "192.0.2.1", &ip("192.0.2.1")).is_none());
+        assert!(store
+            .ban_for("u", "a.example.com", &ip("192.0.2.1"))
+            .is_some());
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::HashSet;
+
+use rand::{distributions::Alphanumeric, Rng};
+
+use super::client::Capability;
+use super::tags::escape_value;
+
+pub fn batch_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(12)
+        .map(char::from)
+        .collect()
+}
+
+/// Adds a `key=value` tag to an already formatted line, merging it into an
+/// existing tag prefix if there is one.
+pub fn add_tag(line: &str, key: &str, value: &str) -> String {
+    match line.strip_prefix('@') {
+        Some(rest) => format!("@{}={};{}", key, escape_value(value), rest),
+        None => format!("@{}={} {}", key, escape_value(value), line),
+    }
+}
+
+/// Wraps `lines` in a `BATCH` of the given type when the client negotiated
+/// `batch`; otherwise the lines are returned untouched.
+pub fn wrap(capabilities: &HashSet<Capability>, kind: &str, lines: Vec<String>) -> Vec<String> {
+    if !capabilities.contains(&Capability::Batch) {
+        return lines;
+    }
+
+    let id = batch_id();
+    let mut wrapped = Vec::with_capacity(lines.len() + 2);
+    wrapped.push(format!(":server BATCH +{} {}\r\n", id, kind));
+    wrapped.extend(tag_batch_members(&id, lines));
+    wrapped.push(format!(":server BATCH -{}\r\n", id));
+    wrapped
+}
+
+/// Applies a client `label` to the replies collected for one command: a
+/// bare `ACK` when there were none, the tag itself on a single reply, or a
+/// `labeled-response` batch around several.
+pub fn label_responses(
+    capabilities: &HashSet<Capability>,
+    label: &str,
+    lines: Vec<String>,
+) -> Vec<String> {
+    match lines.len() {
+        0 => vec![add_tag(":server ACK\r\n", "label", label)],
+        1 => vec![add_tag(&lines[0], "label", label)],
+        _ if capabilities.contains(&Capability::Batch) => {
+            let id = batch_id();
+            let mut wrapped = Vec::with_capacity(lines.len() + 2);
+            wrapped.push(add_tag(
+                &format!(":server BATCH +{} labeled-response\r\n", id),
+                "label",
+                label,
+            ));
+            wrapped.extend(tag_batch_members(&id, lines));
+            wrapped.push(format!(":server BATCH -{}\r\n", id));
+            wrapped
+        }
+        _ => lines,
+    }
+}
+
+/// Tags every top-level line with the batch id. Lines belonging to a nested
+/// batch keep their own tag; only the nested `BATCH` lines are re-parented.
+fn tag_batch_members(id: &str, lines: Vec<String>) -> Vec<String> {
+    let mut depth = 0usize;
+    lines
+        .into_iter()
+        .map(|line| {
+            let command = line
+                .strip_prefix('@')
+                .and_then(|rest| rest.split_once(' ').map(|(_, rest)| rest))
+                .unwrap_or(&line)
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            let is_batch = command == "BATCH";
+            let opens = is_batch && line.contains(" BATCH +");
+            let closes = is_batch && line.contains(" BATCH -");
+
+            if closes {
+                depth = depth.saturating_sub(1);
+            }
+            let tagged = if depth == 0 {
+                add_tag(&line, "batch", id)
+            } else {
+                line
+            };
+            if opens {
+                depth += 1;
+            }
+            tagged
+        })
+        .collect()
+}
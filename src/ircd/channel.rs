@@ -3,16 +3,39 @@ use std::{
     sync::Arc,
 };
 
+use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 use super::client::Client;
+use crate::helpers::mask_matches;
+
+/// Flag modes a channel may carry; `o`, `v` and `b` take parameters and are
+/// handled separately.
+pub const CHANNEL_MODES: &str = "mnst";
+
+/// Modes that take a parameter when set.
+pub const CHANNEL_PARAM_MODES: &str = "bov";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelBan {
+    pub mask: String,
+    pub set_by: String,
+    pub set_at: i64,
+}
 
 #[derive(Debug)]
 pub struct Channel {
     pub name: String,
     pub topic: String,
+    pub topic_set_by: String,
+    pub topic_set_at: i64,
     pub users: HashMap<String, Arc<RwLock<Client>>>,
+    pub operators: HashSet<String>,
+    pub voiced: HashSet<String>,
     pub modes: HashSet<String>,
+    pub bans: Vec<ChannelBan>,
+    /// Modes pinned by the channel registration, e.g. `+nt-s`
+    pub mlock: String,
 }
 
 impl Channel {
@@ -20,8 +43,158 @@ impl Channel {
         Self {
             name,
             topic: String::new(),
+            topic_set_by: String::new(),
+            topic_set_at: 0,
             users: HashMap::new(),
+            operators: HashSet::new(),
+            voiced: HashSet::new(),
             modes: HashSet::new(),
+            bans: vec![],
+            mlock: String::new(),
+        }
+    }
+
+    pub fn is_operator(&self, nick: &str) -> bool {
+        self.operators.contains(nick)
+    }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode.to_string())
+    }
+
+    pub fn mode_string(&self) -> String {
+        let mut modes = self.modes.iter().cloned().collect::<Vec<_>>();
+        modes.sort();
+        format!("+{}", modes.concat())
+    }
+
+    /// Nick with its status prefix; all prefixes with `multi-prefix`,
+    /// otherwise only the highest one.
+    pub fn prefixed_nick(&self, nick: &str, multi_prefix: bool) -> String {
+        let mut prefix = String::new();
+        if self.operators.contains(nick) {
+            prefix.push('@');
+        }
+        if self.voiced.contains(nick) && (multi_prefix || prefix.is_empty()) {
+            prefix.push('+');
+        }
+        format!("{}{}", prefix, nick)
+    }
+
+    pub fn names(&self, multi_prefix: bool) -> String {
+        self.users
+            .keys()
+            .map(|nick| self.prefixed_nick(nick, multi_prefix))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn remove_user(&mut self, nick: &str) {
+        self.users.remove(nick);
+        self.operators.remove(nick);
+        self.voiced.remove(nick);
+    }
+
+    pub fn rename_user(&mut self, old_nick: &str, new_nick: &str) {
+        if let Some(client) = self.users.remove(old_nick) {
+            self.users.insert(new_nick.to_string(), client);
+        }
+        if self.operators.remove(old_nick) {
+            self.operators.insert(new_nick.to_string());
+        }
+        if self.voiced.remove(old_nick) {
+            self.voiced.insert(new_nick.to_string());
         }
     }
+
+    pub fn is_banned(&self, mask: &str) -> bool {
+        self.bans.iter().any(|ban| mask_matches(&ban.mask, mask))
+    }
+
+    /// Whether the mode lock permits setting (`adding`) or unsetting `mode`.
+    pub fn mlock_allows(&self, adding: bool, mode: char) -> bool {
+        let mut locked_on = true;
+        for c in self.mlock.chars() {
+            match c {
+                '+' => locked_on = true,
+                '-' => locked_on = false,
+                c if c == mode => return locked_on == adding,
+                _ => {}
+            }
+        }
+        true
+    }
+
+    /// Forces the channel modes to agree with the mode lock.
+    pub fn apply_mlock(&mut self) {
+        let mut locked_on = true;
+        for c in self.mlock.chars() {
+            match c {
+                '+' => locked_on = true,
+                '-' => locked_on = false,
+                c if CHANNEL_MODES.contains(c) => {
+                    if locked_on {
+                        self.modes.insert(c.to_string());
+                    } else {
+                        self.modes.remove(&c.to_string());
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(mlock: &str) -> Channel {
+        let mut channel = Channel::new("#a".to_string());
+        channel.mlock = mlock.to_string();
+        channel
+    }
+
+    #[test]
+    fn mlock_allows_only_changes_that_agree_with_it() {
+        let channel = locked("+nt-s");
+        assert!(channel.mlock_allows(true, 'n'));
+        assert!(!channel.mlock_allows(false, 'n'));
+        assert!(!channel.mlock_allows(false, 't'));
+        assert!(channel.mlock_allows(false, 's'));
+        assert!(!channel.mlock_allows(true, 's'));
+        assert!(channel.mlock_allows(true, 'm'));
+        assert!(channel.mlock_allows(false, 'm'));
+    }
+
+    #[test]
+    fn mlock_without_a_sign_locks_modes_on() {
+        let channel = locked("n");
+        assert!(!channel.mlock_allows(false, 'n'));
+    }
+
+    #[test]
+    fn an_empty_mlock_allows_everything() {
+        let channel = locked("");
+        for mode in CHANNEL_MODES.chars() {
+            assert!(channel.mlock_allows(true, mode));
+            assert!(channel.mlock_allows(false, mode));
+        }
+    }
+
+    #[test]
+    fn apply_mlock_sets_and_clears_locked_modes() {
+        let mut channel = locked("+nt-s");
+        channel.modes.insert("s".to_string());
+        channel.modes.insert("m".to_string());
+        channel.apply_mlock();
+        assert_eq!(channel.mode_string(), "+mnt");
+    }
+
+    #[test]
+    fn apply_mlock_ignores_modes_that_are_not_flags() {
+        let mut channel = locked("+bov-x");
+        channel.apply_mlock();
+        assert_eq!(channel.mode_string(), "+");
+    }
 }
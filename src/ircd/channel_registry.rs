@@ -0,0 +1,206 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use chrono::{SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::channel::{Channel, ChannelBan, CHANNEL_MODES};
+use crate::configuration::ChannelsConfig;
+use crate::helpers::SnapshotWriter;
+
+/// Access flags: auto-op, auto-voice, topic changes and channel settings.
+pub const ACCESS_FLAGS: &str = "ovts";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccessEntry {
+    pub account: String,
+    pub flags: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredChannel {
+    pub name: String,
+    pub founder: String,
+    pub access: Vec<AccessEntry>,
+    pub topic: String,
+    pub topic_set_by: String,
+    pub topic_set_at: i64,
+    pub modes: String,
+    pub bans: Vec<ChannelBan>,
+    pub mlock: String,
+    pub registered_at: String,
+}
+
+impl RegisteredChannel {
+    /// Access flags held by `account`; the founder holds all of them.
+    pub fn flags_for(&self, account: &str) -> String {
+        if self.founder.eq_ignore_ascii_case(account) {
+            return ACCESS_FLAGS.to_string();
+        }
+        self.access
+            .iter()
+            .find(|entry| entry.account.eq_ignore_ascii_case(account))
+            .map(|entry| entry.flags.clone())
+            .unwrap_or_default()
+    }
+
+    /// Loads the persisted topic, modes and bans into a freshly created
+    /// channel.
+    pub fn restore(&self, channel: &mut Channel) {
+        channel.topic = self.topic.clone();
+        channel.topic_set_by = self.topic_set_by.clone();
+        channel.topic_set_at = self.topic_set_at;
+        channel.modes = self
+            .modes
+            .chars()
+            .filter(|mode| CHANNEL_MODES.contains(*mode))
+            .map(String::from)
+            .collect();
+        channel.bans = self.bans.clone();
+        channel.mlock = self.mlock.clone();
+        channel.apply_mlock();
+    }
+
+    fn snapshot(&mut self, channel: &Channel) {
+        self.topic = channel.topic.clone();
+        self.topic_set_by = channel.topic_set_by.clone();
+        self.topic_set_at = channel.topic_set_at;
+        self.modes = channel.mode_string().trim_start_matches('+').to_string();
+        self.bans = channel.bans.clone();
+        self.mlock = channel.mlock.clone();
+    }
+}
+
+/// Registered channels keyed by lowercased name, persisted the same way as
+/// the account store.
+#[derive(Debug)]
+pub struct ChannelRegistry {
+    channels: HashMap<String, RegisteredChannel>,
+    writer: Option<SnapshotWriter>,
+}
+
+impl ChannelRegistry {
+    pub fn load(config: &ChannelsConfig) -> std::io::Result<Self> {
+        let mut registry = Self {
+            channels: HashMap::new(),
+            writer: None,
+        };
+
+        if let Some(path) = config.file.as_ref().map(PathBuf::from) {
+            if path.exists() {
+                let channels: Vec<RegisteredChannel> =
+                    serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+                registry.channels = channels
+                    .into_iter()
+                    .map(|channel| (channel.name.to_lowercase(), channel))
+                    .collect();
+                tracing::info!(
+                    "Loaded {} registered channels from {}",
+                    registry.channels.len(),
+                    path.display()
+                );
+            }
+            registry.writer = Some(SnapshotWriter::spawn(path, "channel registrations"));
+        }
+
+        Ok(registry)
+    }
+
+    fn save(&self) {
+        if let Some(writer) = &self.writer {
+            writer.save(&self.channels.values().collect::<Vec<_>>());
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&RegisteredChannel> {
+        self.channels.get(&name.to_lowercase())
+    }
+
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    pub fn register(&mut self, channel: &Channel, founder: &str) {
+        let mut registration = RegisteredChannel {
+            name: channel.name.clone(),
+            founder: founder.to_string(),
+            access: vec![],
+            topic: String::new(),
+            topic_set_by: String::new(),
+            topic_set_at: 0,
+            modes: String::new(),
+            bans: vec![],
+            mlock: String::new(),
+            registered_at: Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+        };
+        registration.snapshot(channel);
+        self.channels
+            .insert(channel.name.to_lowercase(), registration);
+        self.save();
+    }
+
+    pub fn drop_channel(&mut self, name: &str) {
+        self.channels.remove(&name.to_lowercase());
+        self.save();
+    }
+
+    /// Persists the current topic, modes and bans of a registered channel;
+    /// unregistered channels are ignored.
+    pub fn sync(&mut self, channel: &Channel) {
+        let Some(registration) = self.channels.get_mut(&channel.name.to_lowercase()) else {
+            return;
+        };
+        registration.snapshot(channel);
+        self.save();
+    }
+
+    /// Sets the mode lock, which is applied to the channel whenever it is
+    /// next created.
+    pub fn set_mlock(&mut self, name: &str, mlock: &str) {
+        let Some(registration) = self.channels.get_mut(&name.to_lowercase()) else {
+            return;
+        };
+        registration.mlock = mlock.to_string();
+        self.save();
+    }
+
+    /// Sets an account's access flags, removing the entry when `flags` is
+    /// empty.
+    pub fn set_access(&mut self, name: &str, account: &str, flags: &str) {
+        let Some(registration) = self.channels.get_mut(&name.to_lowercase()) else {
+            return;
+        };
+        registration
+            .access
+            .retain(|entry| !entry.account.eq_ignore_ascii_case(account));
+        if !flags.is_empty() {
+            registration.access.push(AccessEntry {
+                account: account.to_string(),
+                flags: flags.to_string(),
+            });
+        }
+        self.save();
+    }
+
+    /// Forgets a dropped account, so whoever registers the name next
+    /// inherits nothing: the channels it founded are dropped and its access
+    /// entries elsewhere removed. Returns the names of the dropped channels.
+    pub fn forget_account(&mut self, account: &str) -> Vec<String> {
+        let founded = self
+            .channels
+            .values()
+            .filter(|registration| registration.founder.eq_ignore_ascii_case(account))
+            .map(|registration| registration.name.clone())
+            .collect::<Vec<_>>();
+        for name in &founded {
+            self.channels.remove(&name.to_lowercase());
+        }
+        for registration in self.channels.values_mut() {
+            registration
+                .access
+                .retain(|entry| !entry.account.eq_ignore_ascii_case(account));
+        }
+        self.save();
+        founded
+    }
+}
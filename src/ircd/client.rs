@@ -1,22 +1,101 @@
 use std::collections::HashSet;
-use tokio::sync::mpsc::UnboundedSender;
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::configuration::Privilege;
 
 use super::command::Command;
+use super::oper::Oper;
+use super::sasl::SaslSession;
+use super::sendq::SendQueue;
+use super::snomask::Snomask;
+use super::tags::MessageTags;
+
+/// User modes the server supports: invisible, oper, server notices and
+/// wallops.
+pub const USER_MODES: &str = "iosw";
 
 #[derive(Eq, Hash, PartialEq, Debug, Clone)]
 pub enum Capability {
     MultiPrefix,
     SASL,
     EchoMessage,
+    ServerTime,
+    MessageTags,
+    ChatHistory,
+    Batch,
+    LabeledResponse,
+    AccountRegistration,
+    AccountNotify,
+    AccountTag,
+    ExtendedJoin,
+    Tls,
+}
+
+impl Capability {
+    pub const SUPPORTED: [Capability; 13] = [
+        Capability::MultiPrefix,
+        Capability::SASL,
+        Capability::EchoMessage,
+        Capability::ServerTime,
+        Capability::MessageTags,
+        Capability::ChatHistory,
+        Capability::Batch,
+        Capability::LabeledResponse,
+        Capability::AccountRegistration,
+        Capability::AccountNotify,
+        Capability::AccountTag,
+        Capability::ExtendedJoin,
+        Capability::Tls,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Capability::MultiPrefix => "multi-prefix",
+            Capability::SASL => "sasl",
+            Capability::EchoMessage => "echo-message",
+            Capability::ServerTime => "server-time",
+            Capability::MessageTags => "message-tags",
+            Capability::ChatHistory => "draft/chathistory",
+            Capability::Batch => "batch",
+            Capability::LabeledResponse => "labeled-response",
+            Capability::AccountRegistration => "draft/account-registration",
+            Capability::AccountNotify => "account-notify",
+            Capability::AccountTag => "account-tag",
+            Capability::ExtendedJoin => "extended-join",
+            Capability::Tls => "tls",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::SUPPORTED.into_iter().find(|cap| cap.name() == name)
+    }
 }
 
 #[derive(Debug)]
 pub struct Client {
     pub nick: Option<String>,
     pub user: Option<String>,
+    pub realname: String,
+    pub host: String,
+    pub ip: IpAddr,
+    /// WEBIRC gateway the client connected through
+    pub gateway: Option<String>,
+    /// Name of the listener the client connected through
+    pub listener: String,
+    pub account: Option<String>,
+    /// Connected over TLS
+    pub secure: bool,
+    /// SHA-256 fingerprint of the TLS client certificate, as lowercase hex
+    pub certfp: Option<String>,
+    /// Set once the client has used OPER
+    pub oper: Option<Oper>,
+    pub modes: HashSet<char>,
+    /// Server notices received while `+s` is set
+    pub snomask: HashSet<Snomask>,
+    pub sasl: Option<SaslSession>,
     pub capabilities: HashSet<Capability>,
     pub state: ClientState,
-    pub sender: UnboundedSender<String>,
+    pub sender: SendQueue,
 }
 
 #[derive(Debug)]
@@ -27,44 +106,58 @@ pub enum ClientState {
 }
 
 impl Client {
-    pub fn new(nickname: String, sender: UnboundedSender<String>) -> Self {
+    pub fn new(nickname: String, sender: SendQueue) -> Self {
         Self {
             nick: Some(nickname.clone()),
             user: Some(nickname.clone()),
+            realname: nickname.clone(),
+            host: String::from("unknown"),
+            ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            gateway: None,
+            listener: String::new(),
+            account: None,
+            secure: false,
+            certfp: None,
+            oper: None,
+            modes: HashSet::new(),
+            snomask: HashSet::new(),
+            sasl: None,
             capabilities: HashSet::new(),
             state: ClientState::Unregistered,
             sender,
         }
     }
 
-    #[tracing::instrument(name = "Handling cap commands")]
+    /// Sets the client's address, using it as the displayed host too.
+    pub fn set_address(&mut self, ip: IpAddr) {
+        self.ip = ip;
+        self.host = ip.to_string();
+        //a leading colon would be read as the start of a trailing parameter
+        if self.host.starts_with(':') {
+            self.host.insert(0, '0');
+        }
+    }
+
+    #[tracing::instrument(name = "Handling cap commands", skip(self))]
     pub fn handle_cap_command(&mut self, cmd: &Command) -> Option<String> {
         match cmd {
             Command::CapLs => {
-                let caps = ["multi-prefix", "sasl", "echo-message"].join(" ");
+                let caps = Capability::SUPPORTED
+                    .iter()
+                    .map(Capability::name)
+                    .collect::<Vec<_>>()
+                    .join(" ");
                 tracing::debug!("Sending CAP * LS response");
                 Some(format!("CAP * LS :{}\r\n", caps))
             }
 
             Command::CapReq(requested_caps) => {
-                let supported_caps = [":multi-prefix", ":sasl", ":echo-message"];
                 let mut ack_caps = vec![];
 
                 for cap in requested_caps {
-                    if supported_caps.contains(&cap.as_str()) {
-                        match cap.as_str() {
-                            ":multi-prefix" => {
-                                self.capabilities.insert(Capability::MultiPrefix);
-                            }
-                            ":sasl" => {
-                                self.capabilities.insert(Capability::SASL);
-                            }
-                            ":echo-message" => {
-                                self.capabilities.insert(Capability::EchoMessage);
-                            }
-                            _ => {}
-                        }
-                        ack_caps.push(cap.clone());
+                    if let Some(capability) = Capability::from_name(cap.trim_start_matches(':')) {
+                        self.capabilities.insert(capability);
+                        ack_caps.push(cap.trim_start_matches(':').to_string());
                     }
                 }
 
@@ -83,4 +176,44 @@ impl Client {
             _ => Some("CAP * NAK :Invalid command\r\n".to_string()),
         }
     }
+
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    /// The client's modes as a `+` prefixed string, e.g. `+iw`.
+    pub fn mode_string(&self) -> String {
+        let mut modes: Vec<char> = self.modes.iter().copied().collect();
+        modes.sort_unstable();
+        format!("+{}", modes.into_iter().collect::<String>())
+    }
+
+    /// The snomask letters as a `+` prefixed string, e.g. `+ckq`.
+    pub fn snomask_string(&self) -> String {
+        let mut letters: Vec<char> = self.snomask.iter().map(Snomask::letter).collect();
+        letters.sort_unstable();
+        format!("+{}", letters.into_iter().collect::<String>())
+    }
+
+    pub fn has_privilege(&self, privilege: Privilege) -> bool {
+        self.oper
+            .as_ref()
+            .is_some_and(|oper| oper.privileges.contains(&privilege))
+    }
+
+    /// The `nick!user@host` source used to identify this client.
+    pub fn mask(&self) -> String {
+        format!(
+            "{}!{}@{}",
+            self.nick.as_deref().unwrap_or("*"),
+            self.user.as_deref().unwrap_or("*"),
+            self.host
+        )
+    }
+
+    pub fn send_tagged(&self, tags: &MessageTags, line: &str) {
+        let _ = self
+            .sender
+            .send(format!("{}{}", tags.render(&self.capabilities), line));
+    }
 }
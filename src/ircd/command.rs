@@ -1,12 +1,28 @@
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
 use std::sync::Arc;
 
+use secrecy::{ExposeSecret, Secret};
 use tokio::sync::RwLock;
 
+use crate::configuration::Privilege;
+use crate::helpers::{mask_matches, verify_password, Cidr};
+
 use super::{
-    channel::Channel,
-    client::Client,
+    accounts::{self, log_in, AccountError},
+    bans::{self, BanKind, ServerBan},
+    batch,
+    channel::{Channel, ChannelBan, CHANNEL_MODES},
+    client::{Capability, Client, ClientState, USER_MODES},
+    history::{dm_key, HistoryEntry, Selector},
     ircd::SharedServerState,
+    oper,
     response::{ResponseCode, ResponseParams},
+    sasl,
+    sendq::CloseReason,
+    services::{self, nickserv},
+    snomask::Snomask,
+    tags::{split_tags, MessageTags},
 };
 
 #[derive(Debug)]
@@ -15,18 +31,43 @@ pub enum Command {
     CapReq(Vec<String>),
     CapEnd,
     NICK(String),
-    USER(String),
+    USER(String, String),
     JOIN(String),
     PART(String),
     PING(String),
+    PONG(String),
     PRIVMSG(String, String),
+    NOTICE(String, String),
+    TAGMSG(String, Vec<(String, String)>),
     NAMES(Option<String>),
+    WHO(String),
+    CHATHISTORY(String, Vec<String>),
+    AUTHENTICATE(String),
+    REGISTER(String, String, Secret<String>),
+    VERIFY(String, String),
+    WHOIS(String),
+    STATS(Option<String>),
+    OPER(String, Secret<String>),
+    REHASH,
+    MOTD,
+    ADMIN,
+    DIE,
+    KILL(String, Option<String>),
+    WALLOPS(String),
+    GLOBOPS(String),
+    XLINE(BanKind, Option<u64>, String, Option<String>),
+    UNXLINE(BanKind, String),
+    MODE(String, Option<String>, Vec<String>),
+    TOPIC(String, Option<String>),
+    STARTTLS,
+    WEBIRC(Secret<String>, String, String, String, Vec<String>),
     QUIT,
     Unknown(String),
 }
 
 impl Command {
     pub fn parse(input: &str) -> Self {
+        let (tags, input) = split_tags(input);
         let parts: Vec<&str> = input.split_whitespace().collect();
 
         match parts.first().map(|s| s.to_ascii_uppercase()) {
@@ -48,6 +89,14 @@ impl Command {
                 }
             }
 
+            Some(cmd) if cmd == "PONG" => Command::PONG(
+                parts
+                    .last()
+                    .filter(|_| parts.len() > 1)
+                    .map(|token| token.trim_start_matches(':').to_string())
+                    .unwrap_or_default(),
+            ),
+
             Some(cmd) if cmd == "PING" => {
                 if let Some(token) = parts.get(1) {
                     Command::PING(token.to_string())
@@ -58,7 +107,12 @@ impl Command {
 
             Some(cmd) if cmd == "USER" => {
                 if let Some(user) = parts.get(1) {
-                    Command::USER(user.to_string())
+                    let realname = parts
+                        .get(4..)
+                        .map(|rest| rest.join(" ").trim_start_matches(':').to_string())
+                        .filter(|realname| !realname.is_empty())
+                        .unwrap_or_else(|| user.to_string());
+                    Command::USER(user.to_string(), realname)
                 } else {
                     Command::Unknown(input.to_string())
                 }
@@ -80,22 +134,148 @@ impl Command {
                 }
             }
 
-            Some(cmd) if cmd == "PRIVMSG" => match (parts.get(1), parts.len() > 2) {
-                (Some(target), true) => {
-                    let msg = parts[2..]
-                        .join(" ")
-                        .trim_start_matches(":")
-                        .trim()
-                        .to_string();
-                    if !msg.is_empty() {
-                        Command::PRIVMSG(target.to_string(), msg.to_string())
-                    } else {
-                        Command::Unknown(input.to_string())
-                    }
+            Some(cmd) if cmd == "PRIVMSG" => match message_params(&parts) {
+                Some((target, msg)) => Command::PRIVMSG(target, msg),
+                None => Command::Unknown(input.to_string()),
+            },
+
+            Some(cmd) if cmd == "NOTICE" => match message_params(&parts) {
+                Some((target, msg)) => Command::NOTICE(target, msg),
+                None => Command::Unknown(input.to_string()),
+            },
+
+            Some(cmd) if cmd == "TAGMSG" => {
+                if let Some(target) = parts.get(1) {
+                    Command::TAGMSG(target.to_string(), tags)
+                } else {
+                    Command::Unknown(input.to_string())
+                }
+            }
+
+            Some(cmd) if cmd == "NICKSERV" || cmd == "NS" => {
+                let msg = parts.get(1..).unwrap_or_default().join(" ");
+                Command::PRIVMSG(services::NICKSERV.to_string(), msg)
+            }
+
+            Some(cmd) if cmd == "AUTHENTICATE" => {
+                if let Some(param) = parts.get(1) {
+                    Command::AUTHENTICATE(param.to_string())
+                } else {
+                    Command::Unknown(input.to_string())
                 }
+            }
+
+            Some(cmd) if cmd == "REGISTER" => match parts.as_slice() {
+                [_, account, email, password, ..] => Command::REGISTER(
+                    account.to_string(),
+                    email.to_string(),
+                    Secret::new(password.trim_start_matches(':').to_string()),
+                ),
                 _ => Command::Unknown(input.to_string()),
             },
 
+            Some(cmd) if cmd == "VERIFY" => match parts.as_slice() {
+                [_, account, code, ..] => Command::VERIFY(account.to_string(), code.to_string()),
+                _ => Command::Unknown(input.to_string()),
+            },
+
+            Some(cmd) if cmd == "WHOIS" => {
+                if let Some(nick) = parts.last().filter(|_| parts.len() > 1) {
+                    Command::WHOIS(nick.to_string())
+                } else {
+                    Command::Unknown(input.to_string())
+                }
+            }
+
+            Some(cmd) if cmd == "STATS" => Command::STATS(parts.get(1).map(|s| s.to_string())),
+
+            Some(cmd) if cmd == "OPER" => match parts.as_slice() {
+                [_, name, password, ..] => Command::OPER(
+                    name.to_string(),
+                    Secret::new(password.trim_start_matches(':').to_string()),
+                ),
+                //opers with a certificate and no password may leave it out
+                [_, name] => Command::OPER(name.to_string(), Secret::new(String::new())),
+                _ => Command::Unknown(input.to_string()),
+            },
+
+            Some(cmd) if cmd == "REHASH" => Command::REHASH,
+
+            Some(cmd) if cmd == "DIE" => Command::DIE,
+
+            Some(cmd) if matches!(cmd.as_str(), "KLINE" | "DLINE" | "GLINE") => {
+                let kind = ban_kind(&cmd);
+                //an optional duration in minutes comes before the mask
+                let duration = parts.get(1).and_then(|arg| arg.parse::<u64>().ok());
+                let rest = &parts[1 + usize::from(duration.is_some())..];
+                match rest.split_first() {
+                    Some((mask, reason)) => {
+                        let reason = Some(reason.join(" ").trim_start_matches(':').to_string())
+                            .filter(|reason| !reason.is_empty());
+                        Command::XLINE(kind, duration, mask.to_string(), reason)
+                    }
+                    None => Command::Unknown(input.to_string()),
+                }
+            }
+
+            Some(cmd) if matches!(cmd.as_str(), "UNKLINE" | "UNDLINE" | "UNGLINE") => {
+                match parts.get(1) {
+                    Some(mask) => Command::UNXLINE(ban_kind(&cmd[2..]), mask.to_string()),
+                    None => Command::Unknown(input.to_string()),
+                }
+            }
+
+            Some(cmd) if cmd == "KILL" => match parts.get(1) {
+                Some(nick) => {
+                    let reason = parts
+                        .get(2..)
+                        .map(|rest| rest.join(" ").trim_start_matches(':').to_string())
+                        .filter(|reason| !reason.is_empty());
+                    Command::KILL(nick.to_string(), reason)
+                }
+                None => Command::Unknown(input.to_string()),
+            },
+
+            Some(cmd) if cmd == "WALLOPS" || cmd == "GLOBOPS" => {
+                let text = parts
+                    .get(1..)
+                    .map(|rest| rest.join(" ").trim_start_matches(':').to_string())
+                    .unwrap_or_default();
+                if text.is_empty() {
+                    Command::Unknown(input.to_string())
+                } else if cmd == "WALLOPS" {
+                    Command::WALLOPS(text)
+                } else {
+                    Command::GLOBOPS(text)
+                }
+            }
+
+            Some(cmd) if cmd == "MOTD" => Command::MOTD,
+
+            Some(cmd) if cmd == "ADMIN" => Command::ADMIN,
+
+            Some(cmd) if cmd == "MODE" => {
+                if let Some(target) = parts.get(1) {
+                    let modestring = parts.get(2).map(|s| s.to_string());
+                    let args = parts.iter().skip(3).map(|s| s.to_string()).collect();
+                    Command::MODE(target.to_string(), modestring, args)
+                } else {
+                    Command::Unknown(input.to_string())
+                }
+            }
+
+            Some(cmd) if cmd == "TOPIC" => {
+                if let Some(channel) = parts.get(1) {
+                    let topic = parts
+                        .get(2..)
+                        .filter(|rest| !rest.is_empty())
+                        .map(|rest| rest.join(" ").trim_start_matches(':').to_string());
+                    Command::TOPIC(channel.to_string(), topic)
+                } else {
+                    Command::Unknown(input.to_string())
+                }
+            }
+
             Some(cmd) if cmd == "NAMES" => {
                 if let Some(channel) = parts.get(1) {
                     Command::NAMES(Some(channel.to_string()))
@@ -104,13 +284,47 @@ impl Command {
                 }
             }
 
+            Some(cmd) if cmd == "WHO" => Command::WHO(
+                parts
+                    .get(1)
+                    .filter(|mask| **mask != "0")
+                    .unwrap_or(&"*")
+                    .to_string(),
+            ),
+
+            Some(cmd) if cmd == "CHATHISTORY" => {
+                if let Some(sub_cmd) = parts.get(1) {
+                    let params = parts.iter().skip(2).map(|s| s.to_string()).collect();
+                    Command::CHATHISTORY(sub_cmd.to_ascii_uppercase(), params)
+                } else {
+                    Command::Unknown(input.to_string())
+                }
+            }
+
+            Some(cmd) if cmd == "STARTTLS" => Command::STARTTLS,
+
+            Some(cmd) if cmd == "WEBIRC" => match parts.as_slice() {
+                [_, password, gateway, hostname, ip, options @ ..] => Command::WEBIRC(
+                    Secret::new(password.to_string()),
+                    gateway.to_string(),
+                    hostname.to_string(),
+                    ip.to_string(),
+                    options
+                        .iter()
+                        .map(|option| option.trim_start_matches(':').to_string())
+                        .filter(|option| !option.is_empty())
+                        .collect(),
+                ),
+                _ => Command::Unknown(input.to_string()),
+            },
+
             Some(cmd) if cmd == "QUIT" => Command::QUIT,
 
             _ => Command::Unknown(input.to_string()),
         }
     }
 
-    #[tracing::instrument(name = "Handling command operation")]
+    #[tracing::instrument(name = "Handling command operation", skip_all)]
     pub async fn handle(
         &self,
         session: &Arc<RwLock<Client>>,
@@ -131,27 +345,52 @@ impl Command {
                 let mut active_session = session.write().await;
                 let old_nick = active_session.nick.as_ref().unwrap().clone();
                 let new_nick = nick.clone();
-                server_state.change_nick(&old_nick, &new_nick).await;
+
+                if new_nick.chars().count() > server_state.config().limits.nick_length {
+                    let params = ResponseParams::new(old_nick).nick(new_nick);
+                    let _ = active_session
+                        .sender
+                        .send(ResponseCode::ERR_ERRONEUSNICKNAME.message(params));
+                    return Ok(true);
+                }
+
+                //checked and taken in one step, so a racing client cannot
+                //take the nick in between
+                let in_use = services::is_service(&new_nick)
+                    || server_state
+                        .change_nick(&old_nick, &new_nick)
+                        .await
+                        .is_err();
+                if in_use {
+                    let params = ResponseParams::new(old_nick).nick(new_nick);
+                    let _ = active_session
+                        .sender
+                        .send(ResponseCode::ERR_NICKNAMEINUSE.message(params));
+                    return Ok(true);
+                }
+
                 tracing::debug!("Finished updating server state");
+                //the nick given while registering is not a change worth reporting
+                if !matches!(active_session.state, ClientState::Unregistered) {
+                    tracing::info!(
+                        snomask = %Snomask::Nick,
+                        "Nick change: {} -> {}",
+                        active_session.mask(),
+                        new_nick
+                    );
+                }
 
                 //update the references in the channel lists
                 let channels = server_state.channels.read().await;
                 for channel in channels.values() {
-                    let mut channel = channel.write().await;
-                    if channel.users.contains_key(&old_nick) {
-                        let client = channel.users.remove(&old_nick).unwrap();
-                        channel.users.insert(new_nick.clone(), client);
-                    }
+                    channel.write().await.rename_user(&old_nick, &new_nick);
                 }
 
                 active_session.nick = Some(nick.clone());
 
-                let _ = active_session
-                    .sender
-                    .send(format!(":server 001 {} :Welcome!\r\n", nick));
-
                 //send NICK message to all connected users
                 let formatted_message = format!(":{} NICK {}\r\n", old_nick, new_nick);
+                let tags = MessageTags::new();
                 let recipient_handles = {
                     let users = server_state.users.read().await;
                     users
@@ -166,14 +405,19 @@ impl Command {
                         .collect::<Vec<_>>()
                 };
                 for handle in recipient_handles {
-                    let client = handle.write().await;
-                    let _ = client.sender.send(formatted_message.clone());
+                    let client = handle.read().await;
+                    client.send_tagged(&tags, &formatted_message);
                 }
+                drop(active_session);
+
+                nickserv::enforce_nick(session.clone(), server_state.clone());
                 Ok(true)
             }
 
-            Command::USER(user) => {
-                session.write().await.user = Some(user.clone());
+            Command::USER(user, realname) => {
+                let mut active_session = session.write().await;
+                active_session.user = Some(user.clone());
+                active_session.realname = realname.clone();
                 Ok(true)
             }
 
@@ -183,41 +427,95 @@ impl Command {
                 if parameters[0] == ":" {
                     //return ERR_NEEDMOREPARAMS
                     tracing::debug!("JOIN command missing channel parameter");
-                    let message = ResponseCode::ERR_NEEDMOREPARAMS.message(ResponseParams::new(
-                        session.read().await.nick.as_ref().unwrap().clone(),
-                    ));
+                    let message = ResponseCode::ERR_NEEDMOREPARAMS.message(
+                        ResponseParams::new(session.read().await.nick.as_ref().unwrap().clone())
+                            .command("JOIN"),
+                    );
                     let _ = session.write().await.sender.send(message);
                     return Ok(true);
                 }
 
-                let nickname = {
+                let (nickname, mask, account, realname, multi_prefix) = {
                     let active_session = session.read().await;
-                    active_session.nick.as_ref().unwrap().clone()
+                    (
+                        active_session.nick.as_ref().unwrap().clone(),
+                        active_session.mask(),
+                        active_session.account.clone(),
+                        active_session.realname.clone(),
+                        active_session
+                            .capabilities
+                            .contains(&Capability::MultiPrefix),
+                    )
                 };
 
-                
+                if channel.chars().count() > server_state.config().limits.channel_length {
+                    let reply = ResponseCode::ERR_BADCHANMASK
+                        .message(ResponseParams::new(nickname).channel(channel.clone()));
+                    send_replies(session, vec![reply]).await;
+                    return Ok(true);
+                }
 
                 tracing::debug!("User {} joining channel {}", nickname, channel);
 
-                let channel_obj = {
+                let (channel_obj, created) = {
                     let mut channels_lock = server_state.channels.write().await;
                     if let Some(channel) = channels_lock.get(channel) {
-                        channel.clone()
+                        (channel.clone(), false)
                     } else {
-                        //channel doesn't exist, create it
-                        let channel_obj = Arc::new(RwLock::new(Channel::new(channel.clone())));
+                        //channel doesn't exist, create it and restore any registered state
+                        let mut channel_obj = Channel::new(channel.clone());
+                        if let Some(registration) =
+                            server_state.channel_registry.read().await.get(channel)
+                        {
+                            registration.restore(&mut channel_obj);
+                        }
+                        let channel_obj = Arc::new(RwLock::new(channel_obj));
                         channels_lock.insert(channel.clone(), channel_obj.clone());
-                        channel_obj
+                        (channel_obj, true)
                     }
                 };
                 tracing::debug!("found/created channel");
-                {
-                    channel_obj
-                        .write()
-                        .await
-                        .users
-                        .insert(nickname.clone(), session.clone());
-                }
+
+                let (registered, access_flags) = {
+                    let registry = server_state.channel_registry.read().await;
+                    let registration = registry.get(channel);
+                    (
+                        registration.is_some(),
+                        registration
+                            .zip(account.as_ref())
+                            .map(|(registration, account)| registration.flags_for(account))
+                            .unwrap_or_default(),
+                    )
+                };
+
+                let auto_modes = {
+                    let mut channel_lock = channel_obj.write().await;
+                    if channel_lock.is_banned(&mask) && access_flags.is_empty() {
+                        drop(channel_lock);
+                        let params = ResponseParams::new(nickname.clone()).channel(channel.clone());
+                        let _ = session
+                            .read()
+                            .await
+                            .sender
+                            .send(ResponseCode::ERR_BANNEDFROMCHAN.message(params));
+                        return Ok(true);
+                    }
+                    channel_lock.users.insert(nickname.clone(), session.clone());
+
+                    let mut auto_modes = String::new();
+                    if (created && !registered) || access_flags.contains('o') {
+                        channel_lock.operators.insert(nickname.clone());
+                        auto_modes.push('o');
+                    } else if access_flags.contains('v') {
+                        channel_lock.voiced.insert(nickname.clone());
+                        auto_modes.push('v');
+                    }
+                    //ops on a brand new channel are implied by the JOIN itself
+                    if created && !registered {
+                        auto_modes.clear();
+                    }
+                    auto_modes
+                };
                 tracing::debug!("added user to channel");
                 let channel_name = {
                     let channel_lock = channel_obj.read().await;
@@ -225,39 +523,58 @@ impl Command {
                 };
                 tracing::debug!("Finished updating server state");
 
+                //extended-join carries the account name and realname
+                let join_message = format!(":{} JOIN {}\r\n", nickname, channel_name);
+                let extended_join_message = format!(
+                    ":{} JOIN {} {} :{}\r\n",
+                    nickname,
+                    channel_name,
+                    account.as_deref().unwrap_or("*"),
+                    realname
+                );
+                let join_line = |client: &Client| {
+                    if client.capabilities.contains(&Capability::ExtendedJoin) {
+                        extended_join_message.clone()
+                    } else {
+                        join_message.clone()
+                    }
+                };
+
                 //Send user JOIN message back to the user
                 tracing::debug!("Sending JOIN message to user");
                 let _ = {
                     let active_session = session.read().await;
-                    active_session
-                        .sender
-                        .send(format!(":{} JOIN {}\r\n", nickname, channel_name))
+                    active_session.sender.send(join_line(&active_session))
                 };
 
                 //Send Channel topic value to the user
-                tracing::debug!("Sending channel topic to user");
-                let params = ResponseParams::new(nickname.clone()).channel(channel_name.clone());
-                let response = ResponseCode::RPL_TOPIC.message(params);
-                let _ = {
-                    let active_session = session.read().await;
-                    active_session.sender.send(response)
+                let (topic, topic_set_by, topic_set_at) = {
+                    let channel_lock = channel_obj.read().await;
+                    (
+                        channel_lock.topic.clone(),
+                        channel_lock.topic_set_by.clone(),
+                        channel_lock.topic_set_at,
+                    )
                 };
+                if !topic.is_empty() {
+                    tracing::debug!("Sending channel topic to user");
+                    let params = ResponseParams::new(nickname.clone())
+                        .channel(channel_name.clone())
+                        .message(topic);
+                    let response = ResponseCode::RPL_TOPIC.message(params);
+                    let params = ResponseParams::new(nickname.clone())
+                        .channel(channel_name.clone())
+                        .nick(topic_set_by)
+                        .date(topic_set_at.to_string());
+                    let who_time = ResponseCode::RPL_TOPICWHOTIME.message(params);
+                    let active_session = session.read().await;
+                    let _ = active_session.sender.send(response);
+                    let _ = active_session.sender.send(who_time);
+                }
 
                 //Send name list to user
                 tracing::debug!("Sending name list to user");
-                let user_list = {
-                    let users = server_state
-                        .channels
-                        .read()
-                        .await
-                        .get(channel_name.as_str())
-                        .unwrap()
-                        .read()
-                        .await
-                        .users
-                        .clone();
-                    users.keys().cloned().collect::<Vec<_>>().join(" ")
-                };
+                let user_list = channel_obj.read().await.names(multi_prefix);
                 tracing::debug!("User list: {}", user_list);
                 let params = ResponseParams::new(nickname.clone())
                     .channel(channel_name.clone())
@@ -277,14 +594,14 @@ impl Command {
 
                 //Send join message to all connected users of channel
                 tracing::debug!("Sending JOIN message to all users of channel");
-                let formatted_message = format!(":{} JOIN {}\r\n", nickname, channel_name);
+                let tags = MessageTags::new();
                 let recipient_handles = {
                     let channel_lock = channel_obj.read().await;
                     let users = channel_lock.users.clone();
                     let users_lock = server_state.users.read().await;
                     users
-                        .iter()
-                        .filter_map(|(user, _)| {
+                        .keys()
+                        .filter_map(|user| {
                             if user != &nickname {
                                 users_lock.get(user).map(Arc::clone)
                             } else {
@@ -294,8 +611,22 @@ impl Command {
                         .collect::<Vec<_>>()
                 };
                 for handle in recipient_handles {
-                    let client = handle.write().await;
-                    let _ = client.sender.send(formatted_message.clone());
+                    let client = handle.read().await;
+                    client.send_tagged(&tags, &join_line(&client));
+                }
+
+                //Announce status granted through the channel access list
+                if !auto_modes.is_empty() {
+                    let targets = vec![nickname.as_str(); auto_modes.len()].join(" ");
+                    let formatted_message = format!(
+                        ":{}!{}@services MODE {} +{} {}\r\n",
+                        services::CHANSERV,
+                        services::CHANSERV,
+                        channel_name,
+                        auto_modes,
+                        targets
+                    );
+                    broadcast_channel(&channel_obj, &formatted_message).await;
                 }
                 Ok(true)
             }
@@ -322,16 +653,17 @@ impl Command {
 
                 {
                     let mut channel_lock = channel_obj.write().await;
-                    channel_lock.users.remove(&nickname);
+                    channel_lock.remove_user(&nickname);
                 }
 
                 let formatted_message = format!(":{} PART {}\r\n", nickname, channel_name);
+                let tags = MessageTags::new();
 
                 //Send PART message to user
-                let _ = {
+                {
                     let active_session = session.read().await;
-                    active_session.sender.send(formatted_message.clone())
-                };
+                    active_session.send_tagged(&tags, &formatted_message);
+                }
 
                 let recipient_handles: Vec<Arc<RwLock<Client>>> = {
                     let channel_lock = channel_obj.read().await;
@@ -350,53 +682,29 @@ impl Command {
                 };
 
                 for handle in recipient_handles {
-                    let client = handle.write().await;
-                    let _ = client.sender.send(formatted_message.clone());
+                    let client = handle.read().await;
+                    client.send_tagged(&tags, &formatted_message);
                 }
 
+                server_state.remove_channel_if_empty(&channel_name).await;
                 Ok(true)
             }
 
             Command::PRIVMSG(target, message) => {
-                let nickname = {
-                    let active_session = session.read().await;
-                    active_session.nick.as_ref().unwrap().clone()
-                };
+                if services::dispatch(session, server_state, target, message).await {
+                    return Ok(true);
+                }
+                relay_message(session, server_state, "PRIVMSG", target, Some(message), &[]).await;
+                Ok(true)
+            }
 
-                let formatted_message =
-                    format!(":{} PRIVMSG {} :{}\r\n", nickname, target, message);
-
-                if target.starts_with("#") {
-                    tracing::debug!("Sending message to channel: {}", target);
-                    let recipient_handles: Option<Vec<Arc<RwLock<Client>>>> = {
-                        let channels = server_state.channels.read().await;
-                        if let Some(channel) = channels.get(target) {
-                            let users_lock = server_state.users.read().await;
-                            let channel_lock = channel.read().await;
-                            Some(
-                                channel_lock
-                                    .users
-                                    .keys()
-                                    .filter(|user| *user != &nickname)
-                                    .filter_map(|user| users_lock.get(user).map(Arc::clone))
-                                    .collect::<Vec<_>>(),
-                            )
-                        } else {
-                            Some(vec![])
-                        }
-                    };
+            Command::NOTICE(target, message) => {
+                relay_message(session, server_state, "NOTICE", target, Some(message), &[]).await;
+                Ok(true)
+            }
 
-                    for handle in recipient_handles.unwrap() {
-                        let client = handle.write().await;
-                        let _ = client.sender.send(formatted_message.clone());
-                    }
-                } else if let Some(recipient) = {
-                    let users = server_state.users.read().await;
-                    users.get(target).map(Arc::clone)
-                } {
-                    let client = recipient.write().await;
-                    let _ = client.sender.send(formatted_message);
-                }
+            Command::TAGMSG(target, tags) => {
+                relay_message(session, server_state, "TAGMSG", target, None, tags).await;
                 Ok(true)
             }
 
@@ -409,58 +717,265 @@ impl Command {
                 Ok(true)
             }
 
+            //liveness is tracked by the connection's keepalive
+            Command::PONG(_) => Ok(true),
+
             Command::NAMES(channel) => {
-                let active_session = session.write().await;
-                if let Some(channel) = channel {
+                let (nickname, multi_prefix) = {
+                    let active_session = session.read().await;
+                    (
+                        active_session.nick.clone().unwrap(),
+                        active_session
+                            .capabilities
+                            .contains(&Capability::MultiPrefix),
+                    )
+                };
+                let channel_objs = {
                     let channels = server_state.channels.read().await;
-                    if let Some(channel) = channels.get(channel) {
-                        let channel_lock = channel.read().await;
-                        let users = channel_lock.users.clone();
-                        let user_list = users.keys().cloned().collect::<Vec<_>>().join(" ");
-
-                        let params =
-                            ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
-                                .channel(channel_lock.name.clone())
-                                .message(user_list.clone());
-
-                        let response = ResponseCode::RPL_NAMREPLY.message(params);
-                        let _ = active_session.sender.send(response);
-
-                        let params =
-                            ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
-                                .channel(channel_lock.name.clone());
-                        let response = ResponseCode::RPL_ENDOFNAMES.message(params);
-                        let _ = active_session.sender.send(response);
+                    match channel {
+                        Some(channel) => channels.get(channel).cloned().into_iter().collect(),
+                        None => channels.values().cloned().collect::<Vec<_>>(),
                     }
+                };
+
+                let peers = server_state.channel_peers(&nickname).await;
+                let mut replies = vec![];
+                for channel_obj in channel_objs {
+                    let Some((name, members)) =
+                        visible_members(&channel_obj, &nickname, &peers, multi_prefix).await
+                    else {
+                        continue;
+                    };
+                    let user_list = members
+                        .into_iter()
+                        .map(|(prefixed, _)| prefixed)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    let params = ResponseParams::new(nickname.clone())
+                        .channel(name)
+                        .message(user_list);
+                    replies.push(ResponseCode::RPL_NAMREPLY.message(params));
+                }
+                let params = ResponseParams::new(nickname.clone())
+                    .channel(channel.clone().unwrap_or_else(|| "*".to_string()));
+                replies.push(ResponseCode::RPL_ENDOFNAMES.message(params));
+                send_replies(session, replies).await;
+
+                Ok(true)
+            }
+
+            Command::WHO(mask) => {
+                who(session, server_state, mask).await;
+                Ok(true)
+            }
+
+            Command::CHATHISTORY(sub_cmd, params) => {
+                chathistory(session, server_state, sub_cmd, params).await;
+                Ok(true)
+            }
+
+            Command::AUTHENTICATE(param) => {
+                sasl::authenticate(session, server_state, param).await;
+                Ok(true)
+            }
+
+            Command::REGISTER(account, email, password) => {
+                let (nickname, logged_in) = {
+                    let active_session = session.read().await;
+                    (
+                        active_session.nick.as_ref().unwrap().clone(),
+                        active_session.account.is_some(),
+                    )
+                };
+                let account = if account == "*" {
+                    nickname.clone()
                 } else {
-                    let channels = server_state.channels.read().await;
-                    for channel in channels.keys() {
-                        let channel_obj = channels.get(channel).unwrap();
-                        let channel_lock = channel_obj.read().await;
-                        let user_list = channel_lock
-                            .users
-                            .keys()
-                            .cloned()
-                            .collect::<Vec<_>>()
-                            .join(" ");
-                        let params =
-                            ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
-                                .channel(channel.clone())
-                                .message(user_list.clone());
-
-                        let response = ResponseCode::RPL_NAMREPLY.message(params);
-                        let _ = active_session.sender.send(response);
+                    account.clone()
+                };
+                let email = Some(email.clone()).filter(|email| email != "*");
+
+                let result = if logged_in {
+                    Err("ALREADY_AUTHENTICATED")
+                } else {
+                    accounts::register(server_state, &account, password.expose_secret(), email)
+                        .await
+                        .map_err(|e| match e {
+                            AccountError::AccountExists => "ACCOUNT_EXISTS",
+                            AccountError::WeakPassword => "WEAK_PASSWORD",
+                            AccountError::BadAccountName => "BAD_ACCOUNT_NAME",
+                            _ => "TEMPORARILY_UNAVAILABLE",
+                        })
+                };
+                match result {
+                    Ok(()) => {
+                        let _ = session.read().await.sender.send(format!(
+                            "REGISTER SUCCESS {} :Account successfully registered\r\n",
+                            account
+                        ));
+                        log_in(session, server_state, account).await;
+                    }
+                    Err(code) => {
+                        let _ = session.read().await.sender.send(format!(
+                            "FAIL REGISTER {} {} :Account registration failed\r\n",
+                            code, account
+                        ));
                     }
+                }
+                Ok(true)
+            }
 
-                    let params = ResponseParams::new(active_session.nick.as_ref().unwrap().clone())
-                        .channel("*".to_string());
-                    let response = ResponseCode::RPL_ENDOFNAMES.message(params);
-                    let _ = active_session.sender.send(response);
+            Command::VERIFY(account, code) => {
+                //registrations complete immediately, so there is never anything to verify
+                let _ = session.read().await.sender.send(format!(
+                    "FAIL VERIFY INVALID_CODE {} {} :Invalid verification code\r\n",
+                    account, code
+                ));
+                Ok(true)
+            }
+
+            Command::WHOIS(target) => {
+                whois(session, server_state, target).await;
+                Ok(true)
+            }
+
+            Command::STATS(query) => {
+                stats(session, server_state, query.as_deref()).await;
+                Ok(true)
+            }
+
+            Command::OPER(name, password) => {
+                oper::oper_up(session, server_state, name, password.expose_secret()).await;
+                Ok(true)
+            }
+
+            Command::REHASH => {
+                if oper::require(session, Privilege::Rehash).await {
+                    let nickname = session.read().await.nick.clone().unwrap();
+                    tracing::info!("{} is rehashing the server", nickname);
+                    //the list is a single parameter, so it cannot hold spaces
+                    let files = server_state
+                        .config()
+                        .files
+                        .iter()
+                        .map(|file| file.display().to_string().replace(' ', "\\x20"))
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let params = ResponseParams::new(nickname.clone())
+                        .message(if files.is_empty() { "none" } else { &files });
+                    let reply = ResponseCode::RPL_REHASHING.message(params);
+                    let _ = session.read().await.sender.send(reply);
+                    if let Err(e) = server_state.rehash().await {
+                        tracing::error!("Rehash by {} failed: {}", nickname, e);
+                        let text = format!("*** Rehash failed, configuration unchanged: {}", e);
+                        let _ = session
+                            .read()
+                            .await
+                            .sender
+                            .send(server_notice(&nickname, &text));
+                    }
+                }
+                Ok(true)
+            }
+
+            Command::MOTD => {
+                send_motd(session, server_state).await;
+                Ok(true)
+            }
+
+            Command::ADMIN => {
+                send_admin(session, server_state).await;
+                Ok(true)
+            }
+
+            Command::KILL(target, reason) => {
+                if oper::require(session, Privilege::Kill).await {
+                    kill(session, server_state, target, reason.as_deref()).await;
                 }
+                Ok(true)
+            }
 
+            Command::WALLOPS(text) => {
+                if oper::require(session, Privilege::Wallops).await {
+                    let mask = session.read().await.mask();
+                    tracing::info!("WALLOPS from {}: {}", mask, text);
+                    let line = format!(":{} WALLOPS :{}\r\n", mask, text);
+                    broadcast_users(
+                        server_state,
+                        |client| client.has_mode('w'),
+                        |_| line.clone(),
+                    )
+                    .await;
+                }
                 Ok(true)
             }
 
+            Command::GLOBOPS(text) => {
+                if oper::require(session, Privilege::Globops).await {
+                    let nickname = session.read().await.nick.clone().unwrap();
+                    tracing::info!("GLOBOPS from {}: {}", nickname, text);
+                    let text = format!("*** Global -- from {}: {}", nickname, text);
+                    broadcast_users(
+                        server_state,
+                        |client| client.has_mode('o'),
+                        |client| server_notice(client.nick.as_deref().unwrap_or("*"), &text),
+                    )
+                    .await;
+                }
+                Ok(true)
+            }
+
+            Command::XLINE(kind, duration, mask, reason) => {
+                if oper::require(session, Privilege::Kline).await {
+                    let reason = reason.as_deref().unwrap_or("No reason given");
+                    add_ban(session, server_state, *kind, *duration, mask, reason).await;
+                }
+                Ok(true)
+            }
+
+            Command::UNXLINE(kind, mask) => {
+                if oper::require(session, Privilege::Kline).await {
+                    remove_ban(session, server_state, *kind, mask).await;
+                }
+                Ok(true)
+            }
+
+            Command::DIE => {
+                if oper::require(session, Privilege::Die).await {
+                    let nickname = session.read().await.nick.clone().unwrap();
+                    tracing::warn!("{} is shutting the server down", nickname);
+                    server_state.shutdown("Server shutting down").await;
+                }
+                Ok(true)
+            }
+
+            Command::MODE(target, modestring, args) => {
+                if target.starts_with('#') {
+                    channel_mode(session, server_state, target, modestring.as_deref(), args).await;
+                } else {
+                    user_mode(session, target, modestring.as_deref(), args).await;
+                }
+                Ok(true)
+            }
+
+            Command::TOPIC(channel, topic) => {
+                channel_topic(session, server_state, channel, topic.as_deref()).await;
+                Ok(true)
+            }
+
+            //the stream is upgraded by the connection loop in handle_client
+            Command::STARTTLS => Ok(true),
+
+            Command::WEBIRC(password, gateway, hostname, ip, options) => Ok(webirc(
+                session,
+                server_state,
+                password.expose_secret(),
+                gateway,
+                hostname,
+                ip,
+                options,
+            )
+            .await),
+
             Command::QUIT => {
                 let active_session = session.write().await;
                 let nickname = active_session.nick.as_ref().unwrap();
@@ -471,9 +986,1317 @@ impl Command {
             }
 
             Command::Unknown(cmd) => {
-                tracing::info!("Unknown command: {}", cmd);
+                //only the verb, as the rest may hold credentials
+                let verb = cmd.split_whitespace().next().unwrap_or_default();
+                tracing::info!("Unknown command: {}", verb);
                 Ok(true)
             }
         }
     }
 }
+
+fn message_params(parts: &[&str]) -> Option<(String, String)> {
+    match (parts.get(1), parts.len() > 2) {
+        (Some(target), true) => {
+            let msg = parts[2..]
+                .join(" ")
+                .trim_start_matches(":")
+                .trim()
+                .to_string();
+            if !msg.is_empty() {
+                Some((target.to_string(), msg))
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Lets a trusted gateway replace the client's address with that of the
+/// user it connects for. Returns false when the connection must be closed.
+async fn webirc(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    password: &str,
+    gateway: &str,
+    hostname: &str,
+    ip: &str,
+    options: &[String],
+) -> bool {
+    let source = {
+        let active_session = session.read().await;
+        if active_session.gateway.is_some()
+            || !matches!(active_session.state, ClientState::Unregistered)
+        {
+            return true;
+        }
+        active_session.ip
+    };
+
+    //checked without holding the session, as verifying the password is slow
+    let mut authorised = false;
+    for config in server_state.config().gateways.iter() {
+        if config.name.eq_ignore_ascii_case(gateway)
+            && config.ips.iter().any(|cidr| cidr.contains(&source))
+            && verify_password(password, config.password.expose_secret()).await
+        {
+            authorised = true;
+            break;
+        }
+    }
+    let mut active_session = session.write().await;
+    let Some(ip) = ip.parse::<IpAddr>().ok().filter(|_| authorised) else {
+        tracing::warn!("Rejected WEBIRC for gateway {} from {}", gateway, source);
+        let _ = active_session
+            .sender
+            .send("ERROR :Invalid WEBIRC credentials\r\n".to_string());
+        return false;
+    };
+
+    tracing::info!("WEBIRC gateway {} at {} connects {}", gateway, source, ip);
+    active_session.set_address(ip);
+    if is_valid_hostname(hostname) {
+        active_session.host = hostname.to_string();
+    }
+    //the gateway vouches for the user's own link being encrypted
+    active_session.secure = options
+        .iter()
+        .flat_map(|option| option.split(' '))
+        .any(|option| option == "secure");
+    active_session.gateway = Some(gateway.to_string());
+    true
+}
+
+fn is_valid_hostname(hostname: &str) -> bool {
+    !hostname.is_empty()
+        && !hostname.starts_with([':', '.', '-'])
+        && hostname
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':'))
+}
+
+/// Sends the message of the day, which is also sent on registering.
+pub async fn send_motd(session: &Arc<RwLock<Client>>, server_state: &SharedServerState) {
+    let nickname = session.read().await.nick.clone().unwrap();
+    let params = || ResponseParams::new(nickname.clone());
+    let motd = server_state.motd();
+    let mut replies = vec![];
+    if motd.is_empty() {
+        replies.push(ResponseCode::ERR_NOMOTD.message(params()));
+    } else {
+        let server_name = server_state.config().server_name.clone();
+        replies.push(ResponseCode::RPL_MOTDSTART.message(params().server(server_name)));
+        for line in motd.iter() {
+            replies.push(ResponseCode::RPL_MOTD.message(params().message(line.clone())));
+        }
+        replies.push(ResponseCode::RPL_ENDOFMOTD.message(params()));
+    }
+    send_replies(session, replies).await;
+}
+
+async fn send_admin(session: &Arc<RwLock<Client>>, server_state: &SharedServerState) {
+    let nickname = session.read().await.nick.clone().unwrap();
+    let params = || ResponseParams::new(nickname.clone());
+    let config = server_state.config();
+    let replies = vec![
+        ResponseCode::RPL_ADMINME.message(params().server(config.server_name.clone())),
+        ResponseCode::RPL_ADMINLOC1.message(params().message(config.admin.location.clone())),
+        ResponseCode::RPL_ADMINLOC2.message(params().message(config.admin.organisation.clone())),
+        ResponseCode::RPL_ADMINEMAIL.message(params().message(config.admin.email.clone())),
+    ];
+    send_replies(session, replies).await;
+}
+
+/// Disconnects another user on an oper's behalf. The target's own
+/// connection task notices its queue closing and cleans up as for QUIT.
+async fn kill(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    target: &str,
+    reason: Option<&str>,
+) {
+    let nickname = session.read().await.nick.clone().unwrap();
+    let reply = |line: String| {
+        let session = session.clone();
+        async move {
+            let _ = session.read().await.sender.send(line);
+        }
+    };
+    //nicks cannot contain dots, so any such target names a server
+    let is_server = target.contains('.')
+        || target.eq_ignore_ascii_case("server")
+        || target.eq_ignore_ascii_case(&server_state.config().server_name);
+    if is_server {
+        let params = ResponseParams::new(nickname);
+        return reply(ResponseCode::ERR_CANTKILLSERVER.message(params)).await;
+    }
+    let target_handle = server_state.users.read().await.get(target).map(Arc::clone);
+    let Some(target_handle) = target_handle else {
+        let params = ResponseParams::new(nickname).nick(target);
+        return reply(ResponseCode::ERR_NOSUCHNICK.message(params)).await;
+    };
+
+    let quit = format!(
+        "Killed ({} ({}))",
+        nickname,
+        reason.unwrap_or("No reason given")
+    );
+    tracing::info!(snomask = %Snomask::Kill, "{} killed {}: {}", nickname, target, quit);
+    let target_client = target_handle.read().await;
+    target_client.sender.close_link(CloseReason {
+        error: format!("Closing Link: {} ({})", target_client.host, quit),
+        quit,
+    });
+}
+
+fn ban_kind(command: &str) -> BanKind {
+    match command {
+        "DLINE" => BanKind::Dline,
+        "GLINE" => BanKind::Gline,
+        _ => BanKind::Kline,
+    }
+}
+
+pub fn server_notice(nickname: &str, text: &str) -> String {
+    format!(":server NOTICE {} :{}\r\n", nickname, text)
+}
+
+/// Adds a server ban and drops every connected user it covers.
+async fn add_ban(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    kind: BanKind,
+    duration: Option<u64>,
+    mask: &str,
+    reason: &str,
+) {
+    let nickname = session.read().await.nick.clone().unwrap();
+    let mask = match kind {
+        BanKind::Dline if mask.parse::<Cidr>().is_err() => {
+            let notice = server_notice(&nickname, &format!("Invalid address: {}", mask));
+            let _ = session.read().await.sender.send(notice);
+            return;
+        }
+        BanKind::Kline | BanKind::Gline if !mask.contains('@') => format!("*@{}", mask),
+        _ => mask.to_string(),
+    };
+
+    let now = chrono::Utc::now().timestamp();
+    //a duration of zero is permanent
+    let expires_at = match duration.filter(|minutes| *minutes > 0) {
+        Some(minutes) => {
+            let expires_at = i64::try_from(minutes)
+                .ok()
+                .and_then(|minutes| minutes.checked_mul(60))
+                .and_then(|seconds| now.checked_add(seconds));
+            if expires_at.is_none() {
+                let text = format!("Invalid duration: {} minutes", minutes);
+                let _ = session
+                    .read()
+                    .await
+                    .sender
+                    .send(server_notice(&nickname, &text));
+                return;
+            }
+            expires_at
+        }
+        None => None,
+    };
+    let ban = ServerBan {
+        kind,
+        mask: mask.clone(),
+        reason: reason.to_string(),
+        set_by: nickname.clone(),
+        set_at: now,
+        expires_at,
+    };
+    let covers_setter = {
+        let active_session = session.read().await;
+        let user = active_session.user.clone().unwrap_or_default();
+        ban.matches(&user, &active_session.host, &active_session.ip)
+    };
+    if ban.matches_everyone() || covers_setter {
+        let whom = if covers_setter && !ban.matches_everyone() {
+            "you"
+        } else {
+            "everyone"
+        };
+        let text = format!(
+            "Refusing {}-line for {}: it would match {}",
+            kind.letter(),
+            mask,
+            whom
+        );
+        let _ = session
+            .read()
+            .await
+            .sender
+            .send(server_notice(&nickname, &text));
+        return;
+    }
+    server_state.bans.write().await.add(ban);
+    tracing::info!(
+        "{} added {}-line for {}: {}",
+        nickname,
+        kind.letter(),
+        mask,
+        reason
+    );
+    let text = match duration.filter(|minutes| *minutes > 0) {
+        Some(minutes) => format!(
+            "Added {} minute {}-line for {}",
+            minutes,
+            kind.letter(),
+            mask
+        ),
+        None => format!("Added {}-line for {}", kind.letter(), mask),
+    };
+    let _ = session
+        .read()
+        .await
+        .sender
+        .send(server_notice(&nickname, &text));
+
+    let handles = server_state
+        .users
+        .read()
+        .await
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    for handle in handles {
+        bans::drop_if_banned(&handle, server_state).await;
+    }
+}
+
+async fn remove_ban(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    kind: BanKind,
+    mask: &str,
+) {
+    let nickname = session.read().await.nick.clone().unwrap();
+    let mask = match kind {
+        BanKind::Kline | BanKind::Gline if !mask.contains('@') => format!("*@{}", mask),
+        _ => mask.to_string(),
+    };
+    let removed = server_state.bans.write().await.remove(kind, &mask);
+    let text = if removed {
+        tracing::info!("{} removed {}-line for {}", nickname, kind.letter(), mask);
+        format!("Removed {}-line for {}", kind.letter(), mask)
+    } else {
+        format!("No {}-line for {}", kind.letter(), mask)
+    };
+    let _ = session
+        .read()
+        .await
+        .sender
+        .send(server_notice(&nickname, &text));
+}
+
+/// Answers STATS. Only `P`, the listener list with connection counts, is
+/// supported so far.
+async fn stats(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    query: Option<&str>,
+) {
+    let nickname = {
+        let active_session = session.read().await;
+        active_session.nick.as_ref().unwrap().clone()
+    };
+    let Some(query) = query else {
+        let params = ResponseParams::new(nickname).command("STATS");
+        let _ = session
+            .read()
+            .await
+            .sender
+            .send(ResponseCode::ERR_NEEDMOREPARAMS.message(params));
+        return;
+    };
+
+    let mut replies = vec![];
+    if query.eq_ignore_ascii_case("p") {
+        let mut connections = HashMap::<String, u32>::new();
+        let handles = server_state
+            .users
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for handle in handles {
+            *connections
+                .entry(handle.read().await.listener.clone())
+                .or_default() += 1;
+        }
+        for config in &server_state.config().listeners {
+            let mut flags = vec![if config.tls { "tls" } else { "plaintext" }];
+            if config.websocket {
+                flags.push("websocket");
+            }
+            if config.proxy {
+                flags.push("proxy");
+            }
+            if config.is_unix() {
+                flags.push("unix");
+            }
+            let name = config.name();
+            let params = ResponseParams::new(nickname.clone())
+                .count(connections.get(&name).copied().unwrap_or_default())
+                .server(name)
+                .message(flags.join(","));
+            replies.push(ResponseCode::RPL_STATSPLINE.message(params));
+        }
+    } else if let Some(kind) = ["K", "D", "G"]
+        .into_iter()
+        .find(|letter| query.eq_ignore_ascii_case(letter))
+        .map(|letter| ban_kind(&format!("{}LINE", letter)))
+    {
+        if !oper::require(session, Privilege::Kline).await {
+            return;
+        }
+        for ban in server_state.bans.read().await.list(kind) {
+            let params = ResponseParams::new(nickname.clone())
+                .host(ban.mask.clone())
+                .message(format!("{} (set by {})", ban.reason, ban.set_by));
+            replies.push(match kind {
+                BanKind::Dline => ResponseCode::RPL_STATSDLINE.message(params),
+                _ => ResponseCode::RPL_STATSKLINE.message(params.modes(kind.letter())),
+            });
+        }
+    }
+    replies.push(
+        ResponseCode::RPL_ENDOFSTATS
+            .message(ResponseParams::new(nickname).modes(query.to_ascii_uppercase())),
+    );
+    send_replies(session, replies).await;
+}
+
+/// Delivers a PRIVMSG, NOTICE or TAGMSG to a channel or nick, stamping it
+/// with a shared `time`/`msgid` pair and echoing it back when requested.
+async fn relay_message(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    verb: &str,
+    target: &str,
+    text: Option<&str>,
+    client_tags: &[(String, String)],
+) {
+    let (nickname, account) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.as_ref().unwrap().clone(),
+            active_session.account.clone(),
+        )
+    };
+
+    let formatted_message = match text {
+        Some(text) => format!(":{} {} {} :{}\r\n", nickname, verb, target, text),
+        None => format!(":{} {} {}\r\n", nickname, verb, target),
+    };
+    let tags = MessageTags::new()
+        .with_msgid()
+        .with_account(account.clone())
+        .with_client_tags(client_tags);
+
+    let recipient_handles: Option<Vec<Arc<RwLock<Client>>>> = if target.starts_with("#") {
+        tracing::debug!("Sending {} to channel: {}", verb, target);
+        let channels = server_state.channels.read().await;
+        if let Some(channel) = channels.get(target) {
+            let channel_lock = channel.read().await;
+            let is_member = channel_lock.users.contains_key(&nickname);
+            let can_speak = !channel_lock.has_mode('m')
+                || channel_lock.is_operator(&nickname)
+                || channel_lock.voiced.contains(&nickname);
+            if (channel_lock.has_mode('n') && !is_member) || !can_speak {
+                //NOTICE must never trigger automatic replies
+                if verb != "NOTICE" {
+                    let params = ResponseParams::new(nickname.clone()).channel(target);
+                    let _ = session
+                        .read()
+                        .await
+                        .sender
+                        .send(ResponseCode::ERR_CANNOTSENDTOCHAN.message(params));
+                }
+                return;
+            }
+            Some(
+                channel_lock
+                    .users
+                    .iter()
+                    .filter(|(user, _)| *user != &nickname)
+                    .map(|(_, handle)| Arc::clone(handle))
+                    .collect(),
+            )
+        } else {
+            None
+        }
+    } else {
+        let users = server_state.users.read().await;
+        users.get(target).map(|handle| {
+            if target != nickname {
+                vec![Arc::clone(handle)]
+            } else {
+                vec![]
+            }
+        })
+    };
+    let Some(recipient_handles) = recipient_handles else {
+        return;
+    };
+
+    if let Some(text) = text {
+        let (source_account, target_account) = if target.starts_with('#') {
+            (None, None)
+        } else {
+            let target_account = match recipient_handles.first() {
+                Some(handle) => handle.read().await.account.clone(),
+                None => account.clone(),
+            };
+            (account, target_account)
+        };
+        server_state.history.write().await.record(HistoryEntry {
+            msgid: tags.msgid.clone().unwrap_or_default(),
+            time: tags.time.clone(),
+            source: nickname.clone(),
+            verb: verb.to_string(),
+            target: target.to_string(),
+            text: text.to_string(),
+            source_account,
+            target_account,
+        });
+    }
+
+    for handle in recipient_handles {
+        let client = handle.read().await;
+        // TAGMSG carries nothing but tags, so it is only useful to clients
+        // that understand them
+        if text.is_none() && !client.capabilities.contains(&Capability::MessageTags) {
+            continue;
+        }
+        client.send_tagged(&tags, &formatted_message);
+    }
+
+    let active_session = session.read().await;
+    if active_session
+        .capabilities
+        .contains(&Capability::EchoMessage)
+        && (text.is_some()
+            || active_session
+                .capabilities
+                .contains(&Capability::MessageTags))
+    {
+        active_session.send_tagged(&tags, &formatted_message);
+    }
+}
+
+/// Serves the `draft/chathistory` subcommands out of the history buffers,
+/// only exposing channels the requester is currently a member of and
+/// direct messages of the account they are logged in as.
+async fn chathistory(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    sub_cmd: &str,
+    params: &[String],
+) {
+    let (nickname, account) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.as_ref().unwrap().clone(),
+            active_session.account.clone(),
+        )
+    };
+    let fail = |code: &str, context: &str, description: &str| {
+        format!("FAIL CHATHISTORY {} {} :{}\r\n", code, context, description)
+    };
+    let query_limit = server_state.config().history.query_limit;
+    let limit = |index: usize| {
+        params
+            .get(index)
+            .and_then(|limit| limit.parse::<usize>().ok())
+            .map(|limit| limit.min(query_limit))
+    };
+    let selector = |index: usize| params.get(index).and_then(|s| Selector::parse(s));
+
+    if sub_cmd == "TARGETS" {
+        let (Some(Selector::Timestamp(from)), Some(Selector::Timestamp(to)), Some(limit)) =
+            (selector(0), selector(1), limit(2))
+        else {
+            let _ = session.read().await.sender.send(fail(
+                "INVALID_PARAMS",
+                sub_cmd,
+                "Invalid timestamps or limit",
+            ));
+            return;
+        };
+
+        let joined = {
+            let channels = server_state.channels.read().await;
+            let mut joined = vec![];
+            for (name, channel) in channels.iter() {
+                if channel.read().await.users.contains_key(&nickname) {
+                    joined.push(name.clone());
+                }
+            }
+            joined
+        };
+        let targets = server_state
+            .history
+            .write()
+            .await
+            .targets(&from, &to, limit, |key| {
+                joined.iter().any(|name| name == key)
+                    || key.split(' ').any(|name| Some(name) == account.as_deref())
+            });
+
+        let active_session = session.read().await;
+        let lines = targets
+            .into_iter()
+            .map(|(key, time)| {
+                let target = if key.starts_with('#') {
+                    key.as_str()
+                } else {
+                    //the other party, or the requester when talking to themselves
+                    let own = account.as_deref().unwrap_or_default();
+                    key.split(' ').find(|name| *name != own).unwrap_or(own)
+                };
+                format!(":server CHATHISTORY TARGETS {} {}\r\n", target, time)
+            })
+            .collect();
+        for line in batch::wrap(
+            &active_session.capabilities,
+            "draft/chathistory-targets",
+            lines,
+        ) {
+            let _ = active_session.sender.send(line);
+        }
+        return;
+    }
+
+    let Some(target) = params.first() else {
+        let _ = session.read().await.sender.send(fail(
+            "NEED_MORE_PARAMS",
+            sub_cmd,
+            "Missing parameters",
+        ));
+        return;
+    };
+
+    let key = if target.starts_with('#') {
+        let is_member = match server_state.channels.read().await.get(target) {
+            Some(channel) => channel.read().await.users.contains_key(&nickname),
+            None => false,
+        };
+        if !is_member {
+            let _ = session.read().await.sender.send(fail(
+                "INVALID_TARGET",
+                &format!("{} {}", sub_cmd, target),
+                "Messages could not be retrieved",
+            ));
+            return;
+        }
+        target.clone()
+    } else {
+        let Some(account) = &account else {
+            let _ = session.read().await.sender.send(fail(
+                "INVALID_TARGET",
+                &format!("{} {}", sub_cmd, target),
+                "Messages could not be retrieved",
+            ));
+            return;
+        };
+        //an online target is looked up by the account they are using,
+        //anyone else by the account named after the nick
+        let handle = server_state.users.read().await.get(target).cloned();
+        let target_account = match handle {
+            Some(handle) => handle.read().await.account.clone(),
+            None => None,
+        };
+        dm_key(account, target_account.as_deref().unwrap_or(target))
+    };
+
+    let entries = {
+        let mut history = server_state.history.write().await;
+        match (sub_cmd, selector(1), selector(2), limit(2), limit(3)) {
+            ("LATEST", Some(reference), _, Some(limit), _) => {
+                Some(history.latest(&key, &reference, limit))
+            }
+            (_, Some(Selector::Latest), _, _, _) => None,
+            ("BEFORE", Some(reference), _, Some(limit), _) => {
+                Some(history.before(&key, &reference, limit))
+            }
+            ("AFTER", Some(reference), _, Some(limit), _) => {
+                Some(history.after(&key, &reference, limit))
+            }
+            ("AROUND", Some(reference), _, Some(limit), _) => {
+                Some(history.around(&key, &reference, limit))
+            }
+            ("BETWEEN", Some(from), Some(to), _, Some(limit)) => {
+                if matches!(to, Selector::Latest) {
+                    None
+                } else {
+                    Some(history.between(&key, &from, &to, limit))
+                }
+            }
+            _ => None,
+        }
+    };
+
+    let active_session = session.read().await;
+    let Some(entries) = entries else {
+        let message = match sub_cmd {
+            "LATEST" | "BEFORE" | "AFTER" | "AROUND" | "BETWEEN" => fail(
+                "INVALID_PARAMS",
+                sub_cmd,
+                "Invalid message reference or limit",
+            ),
+            _ => fail("UNKNOWN_COMMAND", sub_cmd, "Unknown subcommand"),
+        };
+        let _ = active_session.sender.send(message);
+        return;
+    };
+
+    let lines = entries
+        .into_iter()
+        .map(|entry| {
+            let tags = MessageTags {
+                time: entry.time,
+                msgid: Some(entry.msgid),
+                account: None,
+                client_tags: vec![],
+            };
+            format!(
+                "{}:{} {} {} :{}\r\n",
+                tags.render(&active_session.capabilities),
+                entry.source,
+                entry.verb,
+                entry.target,
+                entry.text
+            )
+        })
+        .collect();
+    for line in batch::wrap(
+        &active_session.capabilities,
+        &format!("chathistory {}", target),
+        lines,
+    ) {
+        let _ = active_session.sender.send(line);
+    }
+}
+
+async fn whois(session: &Arc<RwLock<Client>>, server_state: &SharedServerState, target: &str) {
+    let nickname = {
+        let active_session = session.read().await;
+        active_session.nick.as_ref().unwrap().clone()
+    };
+    let target_handle = server_state.users.read().await.get(target).map(Arc::clone);
+    let Some(target_handle) = target_handle else {
+        let active_session = session.read().await;
+        let params = ResponseParams::new(nickname.clone()).nick(target);
+        let _ = active_session
+            .sender
+            .send(ResponseCode::ERR_NOSUCHNICK.message(params));
+        let params = ResponseParams::new(nickname).nick(target);
+        let _ = active_session
+            .sender
+            .send(ResponseCode::RPL_ENDOFWHOIS.message(params));
+        return;
+    };
+
+    let mut replies = vec![];
+    let params = || ResponseParams::new(nickname.clone()).nick(target);
+    let (user, host, ip, realname, account, secure, certfp, listener, is_oper) = {
+        let client = target_handle.read().await;
+        (
+            client.user.clone().unwrap_or_default(),
+            client.host.clone(),
+            client.ip,
+            client.realname.clone(),
+            client.account.clone(),
+            client.secure,
+            client.certfp.clone(),
+            client.listener.clone(),
+            client.oper.is_some(),
+        )
+    };
+    let see_secret = target == nickname || session.read().await.has_privilege(Privilege::SeeSecret);
+    replies.push(
+        ResponseCode::RPL_WHOISUSER
+            .message(params().user(user).host(host.clone()).message(realname)),
+    );
+
+    let mut channel_names = vec![];
+    for (name, channel) in server_state.channels.read().await.iter() {
+        if channel.read().await.users.contains_key(target) {
+            channel_names.push(name.clone());
+        }
+    }
+    if !channel_names.is_empty() {
+        replies.push(
+            ResponseCode::RPL_WHOISCHANNELS.message(params().message(channel_names.join(" "))),
+        );
+    }
+
+    let config = server_state.config();
+    replies.push(
+        ResponseCode::RPL_WHOISSERVER.message(
+            params()
+                .server(config.server_name.clone())
+                .message(config.description.clone()),
+        ),
+    );
+
+    if let Some(account) = account {
+        let owner = server_state.accounts.read().await.nick_owner(target);
+        if owner.is_some_and(|owner| owner.eq_ignore_ascii_case(&account)) {
+            replies.push(ResponseCode::RPL_WHOISREGNICK.message(params()));
+        }
+        replies.push(ResponseCode::RPL_WHOISACCOUNT.message(params().account(account)));
+    }
+    if is_oper {
+        replies.push(ResponseCode::RPL_WHOISOPERATOR.message(params()));
+    }
+    if secure {
+        replies.push(ResponseCode::RPL_WHOISSECURE.message(params()));
+    }
+    //fingerprints and listeners are only shown to their owner and opers
+    if see_secret {
+        if host != ip.to_string() {
+            replies.push(ResponseCode::RPL_WHOISACTUALLY.message(params().host(ip.to_string())));
+        }
+        if let Some(certfp) = certfp {
+            replies.push(ResponseCode::RPL_WHOISCERTFP.message(params().message(certfp)));
+        }
+        let labelled = server_state
+            .config()
+            .listeners
+            .iter()
+            .any(|config| config.label.as_ref() == Some(&listener));
+        if labelled {
+            replies.push(
+                ResponseCode::RPL_WHOISSPECIAL.message(
+                    params().message(format!("is connected via the {} listener", listener)),
+                ),
+            );
+        }
+    }
+    replies.push(ResponseCode::RPL_ENDOFWHOIS.message(params()));
+
+    let active_session = session.read().await;
+    for reply in replies {
+        let _ = active_session.sender.send(reply);
+    }
+}
+
+/// Queues a line for every user `wanted` picks. Recipients are gathered
+/// before any of them is locked and queueing never waits on a client, so a
+/// slow reader holds up neither the broadcast nor the user list; one that
+/// falls too far behind is dropped by its own send queue limit.
+async fn broadcast_users(
+    server_state: &SharedServerState,
+    wanted: impl Fn(&Client) -> bool,
+    line: impl Fn(&Client) -> String,
+) {
+    let recipient_handles = server_state
+        .users
+        .read()
+        .await
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    for handle in recipient_handles {
+        let client = handle.read().await;
+        if wanted(&client) {
+            let _ = client.sender.send(line(&client));
+        }
+    }
+}
+
+/// Sends `line` to every member of the channel, including the originator.
+async fn broadcast_channel(channel_obj: &Arc<RwLock<Channel>>, line: &str) {
+    let recipient_handles = channel_obj
+        .read()
+        .await
+        .users
+        .values()
+        .cloned()
+        .collect::<Vec<_>>();
+    let tags = MessageTags::new();
+    for handle in recipient_handles {
+        handle.read().await.send_tagged(&tags, line);
+    }
+}
+
+async fn channel_mode(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    target: &str,
+    modestring: Option<&str>,
+    args: &[String],
+) {
+    let nickname = {
+        let active_session = session.read().await;
+        active_session.nick.as_ref().unwrap().clone()
+    };
+    let mut replies = vec![];
+    let params = || ResponseParams::new(nickname.clone()).channel(target);
+
+    let channel_obj = server_state.channels.read().await.get(target).cloned();
+    let Some(channel_obj) = channel_obj else {
+        replies.push(ResponseCode::ERR_NOSUCHCHANNEL.message(params()));
+        return send_replies(session, replies).await;
+    };
+
+    let Some(modestring) = modestring else {
+        let modes = channel_obj.read().await.mode_string();
+        replies.push(ResponseCode::RPL_CHANNELMODEIS.message(params().modes(modes)));
+        return send_replies(session, replies).await;
+    };
+
+    //a bare `b` lists the bans rather than changing anything
+    if args.is_empty() && modestring.trim_start_matches('+') == "b" {
+        for ban in channel_obj.read().await.bans.iter() {
+            replies.push(
+                ResponseCode::RPL_BANLIST.message(
+                    params()
+                        .host(ban.mask.clone())
+                        .nick(ban.set_by.clone())
+                        .date(ban.set_at.to_string()),
+                ),
+            );
+        }
+        replies.push(ResponseCode::RPL_ENDOFBANLIST.message(params()));
+        return send_replies(session, replies).await;
+    }
+
+    let mut channel = channel_obj.write().await;
+    if !channel.is_operator(&nickname) {
+        drop(channel);
+        replies.push(ResponseCode::ERR_CHANOPRIVSNEEDED.message(params()));
+        return send_replies(session, replies).await;
+    }
+
+    let mut args = args.iter();
+    let mut adding = true;
+    let mut applied = String::new();
+    let mut applied_args = vec![];
+    let mut last_sign = None;
+    let mut record = |adding: bool, mode: char, arg: Option<&String>| {
+        if last_sign != Some(adding) {
+            applied.push(if adding { '+' } else { '-' });
+            last_sign = Some(adding);
+        }
+        applied.push(mode);
+        if let Some(arg) = arg {
+            applied_args.push(arg.clone());
+        }
+    };
+
+    for mode in modestring.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            'o' | 'v' => {
+                let Some(member) = args.next() else {
+                    continue;
+                };
+                if !channel.users.contains_key(member) {
+                    replies.push(
+                        ResponseCode::ERR_USERNOTINCHANNEL.message(params().nick(member.clone())),
+                    );
+                    continue;
+                }
+                let members = if mode == 'o' {
+                    &mut channel.operators
+                } else {
+                    &mut channel.voiced
+                };
+                let changed = if adding {
+                    members.insert(member.clone())
+                } else {
+                    members.remove(member)
+                };
+                if changed {
+                    record(adding, mode, Some(member));
+                }
+            }
+            'b' => {
+                let Some(mask) = args.next() else {
+                    continue;
+                };
+                let exists = channel.bans.iter().any(|ban| &ban.mask == mask);
+                if adding && !exists {
+                    channel.bans.push(ChannelBan {
+                        mask: mask.clone(),
+                        set_by: nickname.clone(),
+                        set_at: chrono::Utc::now().timestamp(),
+                    });
+                    record(adding, mode, Some(mask));
+                } else if !adding && exists {
+                    channel.bans.retain(|ban| &ban.mask != mask);
+                    record(adding, mode, Some(mask));
+                }
+            }
+            mode if CHANNEL_MODES.contains(mode) => {
+                if !channel.mlock_allows(adding, mode) {
+                    continue;
+                }
+                let changed = if adding {
+                    channel.modes.insert(mode.to_string())
+                } else {
+                    channel.modes.remove(&mode.to_string())
+                };
+                if changed {
+                    record(adding, mode, None);
+                }
+            }
+            _ => {
+                replies.push(ResponseCode::ERR_UNKNOWNMODE.message(params().modes(mode)));
+            }
+        }
+    }
+
+    if !applied.is_empty() {
+        server_state.channel_registry.write().await.sync(&channel);
+    }
+    drop(channel);
+    send_replies(session, replies).await;
+
+    if !applied.is_empty() {
+        let mut formatted_message = format!(":{} MODE {} {}", nickname, target, applied);
+        for arg in applied_args {
+            formatted_message.push(' ');
+            formatted_message.push_str(&arg);
+        }
+        formatted_message.push_str("\r\n");
+        broadcast_channel(&channel_obj, &formatted_message).await;
+    }
+}
+
+/// Handles MODE on a nick. Users may only see and change their own modes;
+/// `+o` comes from OPER alone and `+s`, which takes the snomask to receive,
+/// is kept for opers.
+async fn user_mode(
+    session: &Arc<RwLock<Client>>,
+    target: &str,
+    modestring: Option<&str>,
+    args: &[String],
+) {
+    let mut active_session = session.write().await;
+    let nickname = active_session.nick.clone().unwrap();
+    let params = || ResponseParams::new(nickname.clone());
+
+    if !target.eq_ignore_ascii_case(&nickname) {
+        let _ = active_session
+            .sender
+            .send(ResponseCode::ERR_USERSDONTMATCH.message(params()));
+        return;
+    }
+    let Some(modestring) = modestring else {
+        let modes = active_session.mode_string();
+        let _ = active_session
+            .sender
+            .send(ResponseCode::RPL_UMODEIS.message(params().modes(modes)));
+        return;
+    };
+
+    let mut args = args.iter();
+    let mut adding = true;
+    let mut applied = String::new();
+    let mut last_sign = None;
+    let mut record = |adding: bool, mode: char| {
+        if last_sign != Some(adding) {
+            applied.push(if adding { '+' } else { '-' });
+            last_sign = Some(adding);
+        }
+        applied.push(mode);
+    };
+    let mut unknown = false;
+    let mut snomask_changed = false;
+    for mode in modestring.chars() {
+        match mode {
+            '+' => adding = true,
+            '-' => adding = false,
+            's' if adding => {
+                if active_session.oper.is_none() {
+                    continue;
+                }
+                let before = active_session.snomask.clone();
+                apply_snomask(&mut active_session.snomask, args.next().map(String::as_str));
+                snomask_changed |= active_session.snomask != before;
+                if active_session.snomask.is_empty() {
+                    if active_session.modes.remove(&'s') {
+                        record(false, 's');
+                    }
+                } else if active_session.modes.insert('s') {
+                    record(true, 's');
+                }
+            }
+            mode if USER_MODES.contains(mode) => {
+                if adding && mode == 'o' {
+                    continue;
+                }
+                let changed = if adding {
+                    active_session.modes.insert(mode)
+                } else {
+                    active_session.modes.remove(&mode)
+                };
+                if !changed {
+                    continue;
+                }
+                record(adding, mode);
+                //server notices are for opers only
+                if !adding && mode == 'o' {
+                    tracing::info!("{} is no longer an operator", nickname);
+                    active_session.oper = None;
+                    if active_session.modes.remove(&'s') {
+                        record(false, 's');
+                    }
+                }
+                if !active_session.has_mode('s') {
+                    active_session.snomask.clear();
+                }
+            }
+            _ => unknown = true,
+        }
+    }
+
+    if unknown {
+        let _ = active_session
+            .sender
+            .send(ResponseCode::ERR_UMODEUNKNOWNFLAG.message(params()));
+    }
+    if !applied.is_empty() {
+        let _ = active_session
+            .sender
+            .send(format!(":{} MODE {} :{}\r\n", nickname, nickname, applied));
+    }
+    if snomask_changed && active_session.has_mode('s') {
+        let snomask = active_session.snomask_string();
+        let _ = active_session
+            .sender
+            .send(ResponseCode::RPL_SNOMASKIS.message(params().modes(snomask)));
+    }
+}
+
+/// Applies a snomask change such as `+ck-q`. Without one, an empty snomask
+/// subscribes to everything.
+fn apply_snomask(snomask: &mut HashSet<Snomask>, change: Option<&str>) {
+    let Some(change) = change else {
+        if snomask.is_empty() {
+            snomask.extend(Snomask::ALL);
+        }
+        return;
+    };
+    let mut adding = true;
+    for letter in change.chars() {
+        match letter {
+            '+' => adding = true,
+            '-' => adding = false,
+            letter => {
+                let Some(kind) = Snomask::from_letter(letter) else {
+                    continue;
+                };
+                if adding {
+                    snomask.insert(kind);
+                } else {
+                    snomask.remove(&kind);
+                }
+            }
+        }
+    }
+}
+
+/// A channel's name and its members as `viewer` sees them, each with its
+/// status prefix. Outsiders see nothing of a secret channel, and of its
+/// invisible members only those among `peers`, the viewer's channel peers.
+async fn visible_members(
+    channel_obj: &Arc<RwLock<Channel>>,
+    viewer: &str,
+    peers: &[Arc<RwLock<Client>>],
+    multi_prefix: bool,
+) -> Option<(String, Vec<(String, Arc<RwLock<Client>>)>)> {
+    let (name, is_member, members) = {
+        let channel = channel_obj.read().await;
+        let is_member = channel.users.contains_key(viewer);
+        if channel.has_mode('s') && !is_member {
+            return None;
+        }
+        let members = channel
+            .users
+            .iter()
+            .map(|(nick, handle)| (channel.prefixed_nick(nick, multi_prefix), handle.clone()))
+            .collect::<Vec<_>>();
+        (channel.name.clone(), is_member, members)
+    };
+    if is_member {
+        return Some((name, members));
+    }
+
+    //client locks are taken only once the channel lock is released
+    let mut visible = vec![];
+    for (prefixed, handle) in members {
+        if peers.iter().any(|peer| Arc::ptr_eq(peer, &handle)) || !handle.read().await.has_mode('i')
+        {
+            visible.push((prefixed, handle));
+        }
+    }
+    Some((name, visible))
+}
+
+/// Handles WHO for a channel or a nick mask. Invisible users are only
+/// listed for people who share a channel with them.
+async fn who(session: &Arc<RwLock<Client>>, server_state: &SharedServerState, mask: &str) {
+    let (nickname, multi_prefix) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.clone().unwrap(),
+            active_session
+                .capabilities
+                .contains(&Capability::MultiPrefix),
+        )
+    };
+
+    //(channel, status prefix, client) for each user to list
+    let mut entries = vec![];
+    let peers = server_state.channel_peers(&nickname).await;
+    if mask.starts_with('#') {
+        let channel_obj = server_state.channels.read().await.get(mask).cloned();
+        if let Some(channel_obj) = channel_obj {
+            if let Some((name, members)) =
+                visible_members(&channel_obj, &nickname, &peers, multi_prefix).await
+            {
+                for (prefixed, handle) in members {
+                    let status = prefixed
+                        .chars()
+                        .take_while(|c| matches!(c, '@' | '+'))
+                        .collect::<String>();
+                    entries.push((name.clone(), status, handle));
+                }
+            }
+        }
+    } else {
+        let candidates = server_state
+            .users
+            .read()
+            .await
+            .iter()
+            .filter(|(nick, _)| mask_matches(mask, nick))
+            .map(|(nick, handle)| (nick.clone(), handle.clone()))
+            .collect::<Vec<_>>();
+        for (nick, handle) in candidates {
+            let visible = nick == nickname
+                || peers.iter().any(|peer| Arc::ptr_eq(peer, &handle))
+                || !handle.read().await.has_mode('i');
+            if visible {
+                entries.push(("*".to_string(), String::new(), handle));
+            }
+        }
+    }
+
+    let server_name = server_state.config().server_name.clone();
+    let mut replies = vec![];
+    for (channel, status, handle) in entries {
+        let client = handle.read().await;
+        let mut flags = String::from("H");
+        if client.has_mode('o') {
+            flags.push('*');
+        }
+        flags.push_str(&status);
+        let params = ResponseParams::new(nickname.clone())
+            .channel(channel)
+            .user(client.user.clone().unwrap_or_default())
+            .host(client.host.clone())
+            .server(server_name.clone())
+            .nick(client.nick.clone().unwrap_or_default())
+            .modes(flags)
+            .message(client.realname.clone());
+        replies.push(ResponseCode::RPL_WHOREPLY.message(params));
+    }
+    replies.push(ResponseCode::RPL_ENDOFWHO.message(ResponseParams::new(nickname).nick(mask)));
+    send_replies(session, replies).await;
+}
+
+async fn channel_topic(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    target: &str,
+    topic: Option<&str>,
+) {
+    let (nickname, account) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.as_ref().unwrap().clone(),
+            active_session.account.clone(),
+        )
+    };
+    let params = || ResponseParams::new(nickname.clone()).channel(target);
+
+    let channel_obj = server_state.channels.read().await.get(target).cloned();
+    let Some(channel_obj) = channel_obj else {
+        let reply = ResponseCode::ERR_NOSUCHCHANNEL.message(params());
+        return send_replies(session, vec![reply]).await;
+    };
+
+    let Some(topic) = topic else {
+        let channel = channel_obj.read().await;
+        let replies = if channel.topic.is_empty() {
+            vec![ResponseCode::RPL_NOTOPIC.message(params())]
+        } else {
+            vec![
+                ResponseCode::RPL_TOPIC.message(params().message(channel.topic.clone())),
+                ResponseCode::RPL_TOPICWHOTIME.message(
+                    params()
+                        .nick(channel.topic_set_by.clone())
+                        .date(channel.topic_set_at.to_string()),
+                ),
+            ]
+        };
+        drop(channel);
+        return send_replies(session, replies).await;
+    };
+
+    let has_topic_access = match &account {
+        Some(account) => server_state
+            .channel_registry
+            .read()
+            .await
+            .get(target)
+            .is_some_and(|registration| registration.flags_for(account).contains('t')),
+        None => false,
+    };
+
+    let topic = topic
+        .chars()
+        .take(server_state.config().limits.topic_length)
+        .collect::<String>();
+    {
+        let mut channel = channel_obj.write().await;
+        if !channel.users.contains_key(&nickname) {
+            drop(channel);
+            let reply = ResponseCode::ERR_NOTONCHANNEL.message(params());
+            return send_replies(session, vec![reply]).await;
+        }
+        if channel.has_mode('t') && !channel.is_operator(&nickname) && !has_topic_access {
+            drop(channel);
+            let reply = ResponseCode::ERR_CHANOPRIVSNEEDED.message(params());
+            return send_replies(session, vec![reply]).await;
+        }
+        channel.topic = topic.clone();
+        channel.topic_set_by = nickname.clone();
+        channel.topic_set_at = chrono::Utc::now().timestamp();
+        server_state.channel_registry.write().await.sync(&channel);
+    }
+
+    let formatted_message = format!(":{} TOPIC {} :{}\r\n", nickname, target, topic);
+    broadcast_channel(&channel_obj, &formatted_message).await;
+}
+
+async fn send_replies(session: &Arc<RwLock<Client>>, replies: Vec<String>) {
+    let active_session = session.read().await;
+    for reply in replies {
+        let _ = active_session.sender.send(reply);
+    }
+}
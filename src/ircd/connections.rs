@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::configuration::ClassConfig;
+use crate::helpers::Cidr;
+
+/// Open connections per class, per address and per range, used to enforce
+/// the limits of each connection class.
+#[derive(Debug, Default)]
+pub struct ConnectionCounts {
+    counts: Mutex<Counts>,
+}
+
+#[derive(Debug, Default)]
+struct Counts {
+    classes: HashMap<String, usize>,
+    ips: HashMap<(String, IpAddr), usize>,
+    ranges: HashMap<(String, Cidr), usize>,
+}
+
+/// A connection counted against its class, released when dropped.
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    counts: Arc<ConnectionCounts>,
+    class: String,
+    ip: IpAddr,
+    range: Cidr,
+}
+
+impl ConnectionCounts {
+    /// Counts a new connection from `ip`, or returns None when that would
+    /// take the class over one of its limits.
+    pub fn admit(self: &Arc<Self>, class: &ClassConfig, ip: IpAddr) -> Option<ConnectionSlot> {
+        let ip = ip.to_canonical();
+        let prefix = if ip.is_ipv4() {
+            class.cidr_v4
+        } else {
+            class.cidr_v6
+        };
+        let range = Cidr::containing(&ip, prefix);
+        let ip_key = (class.name.clone(), ip);
+        let range_key = (class.name.clone(), range);
+
+        let mut counts = self.counts.lock().unwrap();
+        let over = |count: Option<&usize>, limit: Option<usize>| {
+            limit.is_some_and(|limit| count.copied().unwrap_or(0) >= limit)
+        };
+        if over(counts.classes.get(&class.name), class.max_clients)
+            || over(counts.ips.get(&ip_key), class.max_per_ip)
+            || over(counts.ranges.get(&range_key), class.max_per_cidr)
+        {
+            return None;
+        }
+        *counts.classes.entry(class.name.clone()).or_default() += 1;
+        *counts.ips.entry(ip_key).or_default() += 1;
+        *counts.ranges.entry(range_key).or_default() += 1;
+
+        Some(ConnectionSlot {
+            counts: self.clone(),
+            class: class.name.clone(),
+            ip,
+            range,
+        })
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.counts.lock().unwrap();
+        release(&mut counts.classes, self.class.clone());
+        release(&mut counts.ips, (self.class.clone(), self.ip));
+        release(&mut counts.ranges, (self.class.clone(), self.range));
+    }
+}
+
+fn release<K: std::hash::Hash + Eq>(counts: &mut HashMap<K, usize>, key: K) {
+    if let Some(count) = counts.get_mut(&key) {
+        *count -= 1;
+        if *count == 0 {
+            counts.remove(&key);
+        }
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::tags::split_tags;
+use crate::configuration::ClassConfig;
+
+/// Inbound rate limiting for one connection. Lines are queued as they are
+/// read and released at the rate the client's token bucket allows, each
+/// command spending tokens according to how much work it makes for the
+/// server. A client that keeps sending faster than that fills its RecvQ
+/// and is disconnected.
+#[derive(Debug)]
+pub struct FloodControl {
+    queue: VecDeque<String>,
+    /// Bytes of the lines waiting in `queue`
+    queued: usize,
+    recvq: usize,
+    tokens: f64,
+    burst: f64,
+    /// Tokens regained per second
+    rate: f64,
+    updated: Instant,
+    exempt: bool,
+}
+
+impl FloodControl {
+    pub fn new(class: &ClassConfig) -> Self {
+        Self {
+            queue: VecDeque::new(),
+            queued: 0,
+            recvq: class.recvq,
+            tokens: class.flood_burst,
+            burst: class.flood_burst,
+            rate: class.flood_rate,
+            updated: Instant::now(),
+            exempt: class.flood_exempt,
+        }
+    }
+
+    /// Applies the limits of the class the client was moved to. Tokens
+    /// already held are kept, up to the new burst.
+    pub fn set_class(&mut self, class: &ClassConfig) {
+        self.recvq = class.recvq;
+        self.burst = class.flood_burst;
+        self.tokens = self.tokens.min(self.burst);
+        self.rate = class.flood_rate;
+        self.exempt |= class.flood_exempt;
+    }
+
+    /// Stops limiting the connection, for clients trusted not to flood.
+    pub fn exempt(&mut self) {
+        self.exempt = true;
+    }
+
+    /// Queues a line read from the client. Returns false when the RecvQ
+    /// is exceeded and the client should be disconnected.
+    pub fn push(&mut self, line: String) -> bool {
+        self.queued += line.len();
+        self.queue.push_back(line);
+        self.exempt || self.queued <= self.recvq
+    }
+
+    pub fn has_queued(&self) -> bool {
+        !self.queue.is_empty()
+    }
+
+    /// Waits until the next queued line may be handled and returns it.
+    /// Never resolves while the queue is empty. Cancel safe.
+    pub async fn next(&mut self) -> String {
+        let Some(line) = self.queue.front() else {
+            return std::future::pending().await;
+        };
+        let cost = cost(line);
+        if !self.exempt {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.updated).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.updated = now;
+            if self.tokens < cost {
+                let wait = (cost - self.tokens) / self.rate;
+                tokio::time::sleep(Duration::from_secs_f64(wait)).await;
+                self.tokens = cost;
+                self.updated = Instant::now();
+            }
+            self.tokens -= cost;
+        }
+        let line = self.queue.pop_front().unwrap();
+        self.queued -= line.len();
+        line
+    }
+}
+
+/// Tokens a line spends, by command.
+fn cost(line: &str) -> f64 {
+    let (_, line) = split_tags(line);
+    let command = line
+        .split_whitespace()
+        .find(|word| !word.starts_with(':'))
+        .unwrap_or_default()
+        .to_ascii_uppercase();
+    match command.as_str() {
+        "PONG" | "QUIT" => 0.0,
+        "CAP" | "AUTHENTICATE" | "PING" => 0.5,
+        "JOIN" | "PART" | "WHO" | "WHOIS" | "NAMES" | "LIST" | "CHATHISTORY" => 2.0,
+        "NICK" => 3.0,
+        _ => 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn class(burst: f64, rate: f64, recvq: usize) -> ClassConfig {
+        ClassConfig {
+            flood_burst: burst,
+            flood_rate: rate,
+            recvq,
+            ..ClassConfig::default()
+        }
+    }
+
+    /// Virtual time taken to release the next queued line.
+    async fn time_next(flood: &mut FloodControl) -> Duration {
+        let start = Instant::now();
+        flood.next().await;
+        Instant::now() - start
+    }
+
+    #[test]
+    fn costs_skip_tags_and_prefixes() {
+        assert_eq!(cost("PONG :token"), 0.0);
+        assert_eq!(cost("@label=1 :nick!u@h join #a"), 2.0);
+        assert_eq!(cost("NICK other"), 3.0);
+        assert_eq!(cost("PRIVMSG #a :JOIN"), 1.0);
+        assert_eq!(cost(""), 1.0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn burst_is_released_at_once_then_throttled() {
+        let mut flood = FloodControl::new(&class(3.0, 2.0, 8192));
+        for _ in 0..5 {
+            flood.push("PRIVMSG #a :hi".to_string());
+        }
+        for _ in 0..3 {
+            assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        }
+        assert_eq!(time_next(&mut flood).await, Duration::from_millis(500));
+        assert_eq!(time_next(&mut flood).await, Duration::from_millis(500));
+        assert!(!flood.has_queued());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn tokens_refill_up_to_the_burst() {
+        let mut flood = FloodControl::new(&class(2.0, 1.0, 8192));
+        for _ in 0..2 {
+            flood.push("PRIVMSG #a :hi".to_string());
+            flood.next().await;
+        }
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            flood.push("PRIVMSG #a :hi".to_string());
+        }
+        assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        assert_eq!(time_next(&mut flood).await, Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn commands_costing_more_than_the_burst_still_pass() {
+        let mut flood = FloodControl::new(&class(1.0, 1.0, 8192));
+        flood.push("NICK other".to_string());
+        assert_eq!(time_next(&mut flood).await, Duration::from_secs(2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn free_commands_never_wait() {
+        let mut flood = FloodControl::new(&class(0.0, 1.0, 8192));
+        for _ in 0..10 {
+            flood.push("PONG :token".to_string());
+            assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn next_waits_forever_on_an_empty_queue() {
+        let mut flood = FloodControl::new(&ClassConfig::default());
+        let next = tokio::time::timeout(Duration::from_secs(3600), flood.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[test]
+    fn recvq_overflow_is_reported() {
+        let mut flood = FloodControl::new(&class(1.0, 1.0, 20));
+        assert!(flood.push("PRIVMSG #a :0123".to_string()));
+        assert!(!flood.push("PRIVMSG #a :0123".to_string()));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn exempt_clients_are_never_limited() {
+        let mut flood = FloodControl::new(&class(1.0, 1.0, 20));
+        flood.exempt();
+        for _ in 0..10 {
+            assert!(flood.push("PRIVMSG #a :0123".to_string()));
+        }
+        for _ in 0..10 {
+            assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn moving_class_caps_held_tokens() {
+        let mut flood = FloodControl::new(&class(10.0, 1.0, 8192));
+        flood.set_class(&class(1.0, 4.0, 8192));
+        for _ in 0..2 {
+            flood.push("PRIVMSG #a :hi".to_string());
+        }
+        assert_eq!(time_next(&mut flood).await, Duration::ZERO);
+        assert_eq!(time_next(&mut flood).await, Duration::from_millis(250));
+    }
+}
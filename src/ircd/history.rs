@@ -0,0 +1,547 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::configuration::HistoryConfig;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub msgid: String,
+    pub time: String,
+    pub source: String,
+    pub verb: String,
+    pub target: String,
+    pub text: String,
+    /// Accounts of the sender and recipient of a direct message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_account: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target_account: Option<String>,
+}
+
+impl HistoryEntry {
+    /// Channel messages are stored under the channel name, direct messages
+    /// under the pair of accounts taking part in the conversation. Direct
+    /// messages with anyone not logged in are not kept, since nothing would
+    /// stop the next holder of their nick from reading them.
+    pub fn buffer_key(&self) -> Option<String> {
+        if self.target.starts_with('#') {
+            Some(self.target.clone())
+        } else {
+            Some(dm_key(
+                self.source_account.as_ref()?,
+                self.target_account.as_ref()?,
+            ))
+        }
+    }
+}
+
+pub fn dm_key(a: &str, b: &str) -> String {
+    if a <= b {
+        format!("{} {}", a, b)
+    } else {
+        format!("{} {}", b, a)
+    }
+}
+
+/// A CHATHISTORY message reference.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    MsgId(String),
+    Timestamp(String),
+    Latest,
+}
+
+impl Selector {
+    pub fn parse(input: &str) -> Option<Self> {
+        if input == "*" {
+            return Some(Selector::Latest);
+        }
+        match input.split_once('=') {
+            Some(("msgid", msgid)) => Some(Selector::MsgId(msgid.to_string())),
+            Some(("timestamp", timestamp)) => {
+                DateTime::parse_from_rfc3339(timestamp).ok().map(|time| {
+                    Selector::Timestamp(
+                        time.with_timezone(&Utc)
+                            .to_rfc3339_opts(SecondsFormat::Millis, true),
+                    )
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The configured retention age; an age too large to represent is treated
+/// as no limit at all.
+fn max_age(config: &HistoryConfig) -> Option<chrono::Duration> {
+    config
+        .max_age_secs
+        .and_then(|secs| i64::try_from(secs).ok())
+        .and_then(chrono::Duration::try_seconds)
+}
+
+#[derive(Debug)]
+pub struct MessageHistory {
+    buffers: HashMap<String, VecDeque<HistoryEntry>>,
+    max_messages: usize,
+    max_age: Option<chrono::Duration>,
+    journal: Option<mpsc::UnboundedSender<HistoryEntry>>,
+}
+
+impl MessageHistory {
+    pub fn new(config: &HistoryConfig) -> Self {
+        Self {
+            buffers: HashMap::new(),
+            max_messages: config.max_messages,
+            max_age: max_age(config),
+            journal: None,
+        }
+    }
+
+    /// Applies new retention limits; buffers are trimmed as they are next
+    /// added to.
+    pub fn set_limits(&mut self, config: &HistoryConfig) {
+        self.max_messages = config.max_messages;
+        self.max_age = max_age(config);
+    }
+
+    /// Builds the history store, replaying and compacting the on-disk
+    /// journal when one is configured. Must be called inside a runtime.
+    pub fn load(config: &HistoryConfig) -> std::io::Result<Self> {
+        let mut history = Self::new(config);
+        let Some(path) = &config.file else {
+            return Ok(history);
+        };
+        let path = Path::new(path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if path.exists() {
+            let file = std::fs::File::open(path)?;
+            for line in std::io::BufReader::new(file).lines() {
+                match serde_json::from_str::<HistoryEntry>(&line?) {
+                    Ok(entry) => history.insert(entry),
+                    Err(e) => tracing::warn!("Skipping corrupt history entry: {}", e),
+                }
+            }
+        }
+
+        //rewrite the journal so expired entries do not accumulate forever
+        let mut file = std::fs::File::create(path)?;
+        for buffer in history.buffers.values() {
+            for entry in buffer {
+                writeln!(file, "{}", serde_json::to_string(entry)?)?;
+            }
+        }
+        tracing::info!(
+            "Loaded {} history buffers from {}",
+            history.buffers.len(),
+            path.display()
+        );
+
+        let (journal_tx, mut journal_rx) = mpsc::unbounded_channel::<HistoryEntry>();
+        let path = path.to_path_buf();
+        tokio::spawn(async move {
+            let mut file = match tokio::fs::OpenOptions::new().append(true).open(&path).await {
+                Ok(file) => file,
+                Err(e) => {
+                    tracing::error!("Failed to open history journal: {}", e);
+                    return;
+                }
+            };
+            while let Some(entry) = journal_rx.recv().await {
+                let Ok(mut line) = serde_json::to_string(&entry) else {
+                    continue;
+                };
+                line.push('\n');
+                if let Err(e) = file.write_all(line.as_bytes()).await {
+                    tracing::error!("Failed to append to history journal: {}", e);
+                }
+            }
+        });
+        history.journal = Some(journal_tx);
+
+        Ok(history)
+    }
+
+    pub fn record(&mut self, entry: HistoryEntry) {
+        if entry.buffer_key().is_none() {
+            return;
+        }
+        if let Some(journal) = &self.journal {
+            let _ = journal.send(entry.clone());
+        }
+        self.insert(entry);
+    }
+
+    fn insert(&mut self, entry: HistoryEntry) {
+        let Some(key) = entry.buffer_key() else {
+            return;
+        };
+        self.buffers
+            .entry(key.clone())
+            .or_default()
+            .push_back(entry);
+        self.prune(&key);
+    }
+
+    fn prune(&mut self, key: &str) {
+        //an age reaching back before any representable time keeps everything
+        let cutoff = self
+            .max_age
+            .and_then(|age| Utc::now().checked_sub_signed(age))
+            .map(|cutoff| cutoff.to_rfc3339_opts(SecondsFormat::Millis, true));
+        let Some(buffer) = self.buffers.get_mut(key) else {
+            return;
+        };
+        while buffer.len() > self.max_messages {
+            buffer.pop_front();
+        }
+        if let Some(cutoff) = cutoff {
+            while buffer.front().is_some_and(|entry| entry.time < cutoff) {
+                buffer.pop_front();
+            }
+        }
+        if buffer.is_empty() {
+            self.buffers.remove(key);
+        }
+    }
+
+    fn buffer(&mut self, key: &str) -> Vec<HistoryEntry> {
+        self.prune(key);
+        self.buffers
+            .get(key)
+            .map(|buffer| buffer.iter().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn before(&mut self, key: &str, selector: &Selector, limit: usize) -> Vec<HistoryEntry> {
+        let buffer = self.buffer(key);
+        match end_before(&buffer, selector) {
+            Some(end) => last(&buffer[..end], limit),
+            None => vec![],
+        }
+    }
+
+    pub fn after(&mut self, key: &str, selector: &Selector, limit: usize) -> Vec<HistoryEntry> {
+        let buffer = self.buffer(key);
+        match start_after(&buffer, selector) {
+            Some(start) => first(&buffer[start..], limit),
+            None => vec![],
+        }
+    }
+
+    pub fn latest(&mut self, key: &str, selector: &Selector, limit: usize) -> Vec<HistoryEntry> {
+        let buffer = self.buffer(key);
+        match start_after(&buffer, selector) {
+            Some(start) => last(&buffer[start..], limit),
+            None => vec![],
+        }
+    }
+
+    pub fn around(&mut self, key: &str, selector: &Selector, limit: usize) -> Vec<HistoryEntry> {
+        let buffer = self.buffer(key);
+        match end_before(&buffer, selector) {
+            Some(center) => {
+                let start = center.saturating_sub(limit / 2);
+                let end = (start + limit).min(buffer.len());
+                buffer[start..end].to_vec()
+            }
+            None => vec![],
+        }
+    }
+
+    pub fn between(
+        &mut self,
+        key: &str,
+        from: &Selector,
+        to: &Selector,
+        limit: usize,
+    ) -> Vec<HistoryEntry> {
+        let buffer = self.buffer(key);
+        let (Some(from_pos), Some(to_pos)) = (end_before(&buffer, from), end_before(&buffer, to))
+        else {
+            return vec![];
+        };
+
+        if from_pos <= to_pos {
+            let start = start_after(&buffer, from).unwrap_or(from_pos);
+            first(&buffer[start.min(to_pos)..to_pos], limit)
+        } else {
+            let start = start_after(&buffer, to).unwrap_or(to_pos);
+            last(&buffer[start.min(from_pos)..from_pos], limit)
+        }
+    }
+
+    /// Lists the buffers visible through `visible` that saw activity between
+    /// the two timestamps, along with the time of their latest message.
+    pub fn targets(
+        &mut self,
+        from: &str,
+        to: &str,
+        limit: usize,
+        visible: impl Fn(&str) -> bool,
+    ) -> Vec<(String, String)> {
+        let (from, to) = if from <= to { (from, to) } else { (to, from) };
+        let mut targets = self
+            .buffers
+            .iter()
+            .filter(|(key, _)| visible(key))
+            .filter_map(|(key, buffer)| {
+                buffer
+                    .iter()
+                    .rev()
+                    .find(|entry| entry.time.as_str() > from && entry.time.as_str() < to)
+                    .map(|entry| (key.clone(), entry.time.clone()))
+            })
+            .collect::<Vec<_>>();
+        targets.sort_by(|a, b| a.1.cmp(&b.1));
+        targets.truncate(limit);
+        targets
+    }
+}
+
+/// Index of the first entry that is not strictly before the selector.
+fn end_before(buffer: &[HistoryEntry], selector: &Selector) -> Option<usize> {
+    match selector {
+        Selector::MsgId(msgid) => buffer.iter().position(|entry| &entry.msgid == msgid),
+        Selector::Timestamp(time) => Some(buffer.partition_point(|entry| &entry.time < time)),
+        Selector::Latest => Some(buffer.len()),
+    }
+}
+
+/// Index of the first entry strictly after the selector.
+fn start_after(buffer: &[HistoryEntry], selector: &Selector) -> Option<usize> {
+    match selector {
+        Selector::MsgId(msgid) => buffer
+            .iter()
+            .position(|entry| &entry.msgid == msgid)
+            .map(|pos| pos + 1),
+        Selector::Timestamp(time) => Some(buffer.partition_point(|entry| &entry.time <= time)),
+        Selector::Latest => Some(0),
+    }
+}
+
+fn first(entries: &[HistoryEntry], limit: usize) -> Vec<HistoryEntry> {
+    entries.iter().take(limit).cloned().collect()
+}
+
+fn last(entries: &[HistoryEntry], limit: usize) -> Vec<HistoryEntry> {
+    entries[entries.len().saturating_sub(limit)..].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(target: &str, n: u32) -> HistoryEntry {
+        HistoryEntry {
+            msgid: format!("m{}", n),
+            time: format!("2024-01-01T00:00:{:02}.000Z", n),
+            source: "nick!user@host".to_string(),
+            verb: "PRIVMSG".to_string(),
+            target: target.to_string(),
+            text: format!("message {}", n),
+            source_account: None,
+            target_account: None,
+        }
+    }
+
+    /// A channel holding messages `m1` to `m10`, one second apart.
+    fn history() -> MessageHistory {
+        let mut history = MessageHistory::new(&HistoryConfig::default());
+        for n in 1..=10 {
+            history.record(entry("#a", n));
+        }
+        history
+    }
+
+    fn ids(entries: &[HistoryEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.msgid.as_str()).collect()
+    }
+
+    fn msgid(id: &str) -> Selector {
+        Selector::MsgId(id.to_string())
+    }
+
+    fn timestamp(second: u32) -> Selector {
+        Selector::parse(&format!("timestamp=2024-01-01T00:00:{:02}Z", second)).unwrap()
+    }
+
+    #[test]
+    fn parses_selectors() {
+        assert!(matches!(Selector::parse("*"), Some(Selector::Latest)));
+        assert!(matches!(
+            Selector::parse("msgid=abc"),
+            Some(Selector::MsgId(id)) if id == "abc"
+        ));
+        assert!(matches!(
+            Selector::parse("timestamp=2024-01-01T02:00:00+02:00"),
+            Some(Selector::Timestamp(time)) if time == "2024-01-01T00:00:00.000Z"
+        ));
+        assert!(Selector::parse("timestamp=yesterday").is_none());
+        assert!(Selector::parse("msgid").is_none());
+        assert!(Selector::parse("id=abc").is_none());
+    }
+
+    #[test]
+    fn before_and_after_exclude_the_reference() {
+        let mut history = history();
+        assert_eq!(ids(&history.before("#a", &msgid("m5"), 2)), ["m3", "m4"]);
+        assert_eq!(ids(&history.after("#a", &msgid("m5"), 2)), ["m6", "m7"]);
+        assert_eq!(ids(&history.before("#a", &timestamp(3), 10)), ["m1", "m2"]);
+        assert_eq!(ids(&history.after("#a", &timestamp(8), 10)), ["m9", "m10"]);
+    }
+
+    #[test]
+    fn latest_gives_the_newest_messages() {
+        let mut history = history();
+        assert_eq!(
+            ids(&history.latest("#a", &Selector::Latest, 3)),
+            ["m8", "m9", "m10"]
+        );
+        assert_eq!(ids(&history.latest("#a", &msgid("m8"), 5)), ["m9", "m10"]);
+    }
+
+    #[test]
+    fn around_centres_on_the_reference() {
+        let mut history = history();
+        assert_eq!(
+            ids(&history.around("#a", &msgid("m5"), 4)),
+            ["m3", "m4", "m5", "m6"]
+        );
+        assert_eq!(ids(&history.around("#a", &msgid("m5"), 1)), ["m5"]);
+        assert_eq!(
+            ids(&history.around("#a", &msgid("m1"), 4)),
+            ["m1", "m2", "m3", "m4"]
+        );
+        assert_eq!(
+            ids(&history.around("#a", &msgid("m10"), 4)),
+            ["m8", "m9", "m10"]
+        );
+    }
+
+    #[test]
+    fn between_excludes_both_ends_in_either_order() {
+        let mut history = history();
+        assert_eq!(
+            ids(&history.between("#a", &msgid("m2"), &msgid("m6"), 10)),
+            ["m3", "m4", "m5"]
+        );
+        assert_eq!(
+            ids(&history.between("#a", &msgid("m2"), &msgid("m6"), 2)),
+            ["m3", "m4"]
+        );
+        assert_eq!(
+            ids(&history.between("#a", &msgid("m6"), &msgid("m2"), 2)),
+            ["m4", "m5"]
+        );
+        assert_eq!(
+            ids(&history.between("#a", &timestamp(2), &timestamp(6), 10)),
+            ["m3", "m4", "m5"]
+        );
+        assert!(history
+            .between("#a", &msgid("m5"), &msgid("m5"), 10)
+            .is_empty());
+        assert!(history
+            .between("#a", &msgid("m4"), &msgid("m5"), 10)
+            .is_empty());
+    }
+
+    #[test]
+    fn unknown_references_and_targets_give_nothing() {
+        let mut history = history();
+        assert!(history.before("#a", &msgid("nope"), 10).is_empty());
+        assert!(history.after("#a", &msgid("nope"), 10).is_empty());
+        assert!(history.around("#a", &msgid("nope"), 10).is_empty());
+        assert!(history
+            .between("#a", &msgid("m1"), &msgid("nope"), 10)
+            .is_empty());
+        assert!(history.latest("#b", &Selector::Latest, 10).is_empty());
+    }
+
+    #[test]
+    fn buffers_keep_only_the_newest_messages() {
+        let mut history = MessageHistory::new(&HistoryConfig {
+            max_messages: 3,
+            ..HistoryConfig::default()
+        });
+        for n in 1..=10 {
+            history.record(entry("#a", n));
+        }
+        assert_eq!(
+            ids(&history.latest("#a", &Selector::Latest, 10)),
+            ["m8", "m9", "m10"]
+        );
+    }
+
+    #[test]
+    fn expired_messages_are_dropped() {
+        let mut history = MessageHistory::new(&HistoryConfig {
+            max_age_secs: Some(3600),
+            ..HistoryConfig::default()
+        });
+        history.record(entry("#a", 1));
+        let mut recent = entry("#a", 2);
+        recent.time = Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true);
+        history.record(recent);
+        assert_eq!(ids(&history.latest("#a", &Selector::Latest, 10)), ["m2"]);
+    }
+
+    #[test]
+    fn huge_max_ages_keep_everything() {
+        let mut history = MessageHistory::new(&HistoryConfig {
+            max_age_secs: Some(u64::MAX),
+            ..HistoryConfig::default()
+        });
+        history.record(entry("#a", 1));
+        assert_eq!(ids(&history.latest("#a", &Selector::Latest, 10)), ["m1"]);
+    }
+
+    #[test]
+    fn direct_messages_are_kept_by_account_pair() {
+        let mut dm = entry("bob", 1);
+        assert_eq!(dm.buffer_key(), None);
+        dm.source_account = Some("alice".to_string());
+        assert_eq!(dm.buffer_key(), None);
+        dm.target_account = Some("bob".to_string());
+        assert_eq!(dm.buffer_key(), Some(dm_key("bob", "alice")));
+        assert_eq!(dm_key("bob", "alice"), dm_key("alice", "bob"));
+
+        let mut history = MessageHistory::new(&HistoryConfig::default());
+        history.record(entry("carol", 2));
+        history.record(dm);
+        assert_eq!(
+            ids(&history.latest(&dm_key("alice", "bob"), &Selector::Latest, 10)),
+            ["m1"]
+        );
+        assert!(history.buffers.keys().all(|key| !key.contains("carol")));
+    }
+
+    #[test]
+    fn targets_lists_active_buffers_oldest_first() {
+        let mut history = history();
+        history.record(entry("#b", 4));
+        history.record(entry("#c", 20));
+        let targets = history.targets(
+            "2024-01-01T00:00:30.000Z",
+            "2024-01-01T00:00:00.000Z",
+            10,
+            |key| key != "#c",
+        );
+        assert_eq!(
+            targets,
+            [
+                ("#b".to_string(), "2024-01-01T00:00:04.000Z".to_string()),
+                ("#a".to_string(), "2024-01-01T00:00:10.000Z".to_string()),
+            ]
+        );
+    }
+}
@@ -1,18 +1,60 @@
-use super::channel::Channel;
-use super::client::Client;
-use super::command::Command;
+use super::accounts::AccountStore;
+use super::bans::{self, BanStore};
+use super::batch;
+use super::channel::{Channel, CHANNEL_MODES};
+use super::channel_registry::ChannelRegistry;
+use super::client::{Capability, Client, ClientState};
+use super::command::{self, Command};
+use super::connections::{ConnectionCounts, ConnectionSlot};
+use super::flood::FloodControl;
+use super::history::MessageHistory;
+use super::keepalive::{Expiry, Keepalive};
+use super::listener::Listener;
+use super::oper;
+use super::proxy;
+use super::response::{ResponseCode, ResponseParams};
+use super::sendq::{self, CloseReason, SendQueueReceiver};
+use super::snomask::{self, Snomask, SnomaskEvents};
+use super::tags::{split_tags, MessageTags};
+use super::tls::{self, CertificateStore};
+use super::transport::{ClientStream, Transport};
+use crate::configuration::{get_configuration, ClassConfig, ListenerConfig, ServerConfig};
 use rand::Rng;
 use std::collections::HashMap;
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{mpsc, RwLock};
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Mutex, RwLock};
+use tokio_rustls::TlsAcceptor;
 use tracing::instrument;
 
+/// How long a proxy gets to send its header after connecting
+const PROXY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client gets to complete a TLS or WebSocket handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection the server closes gets to take its final ERROR
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[derive(Debug)]
 pub struct ServerState {
     pub users: RwLock<HashMap<String, Arc<RwLock<Client>>>>,
     pub channels: RwLock<HashMap<String, Arc<RwLock<Channel>>>>,
+    pub history: RwLock<MessageHistory>,
+    pub accounts: RwLock<AccountStore>,
+    pub channel_registry: RwLock<ChannelRegistry>,
+    pub bans: RwLock<BanStore>,
+    pub certificates: Option<Arc<CertificateStore>>,
+    pub connections: Arc<ConnectionCounts>,
+    /// When the server started, as given in RPL_CREATED
+    pub started_at: String,
+    config: std::sync::RwLock<Arc<ServerConfig>>,
+    motd: std::sync::RwLock<Arc<Vec<String>>>,
+    /// Running accept loops by bind address, each following its listener's
+    /// current definition; also serializes rehashes
+    listeners: Mutex<HashMap<String, watch::Sender<ListenerConfig>>>,
 }
 
 impl ServerState {
@@ -23,14 +65,170 @@ impl ServerState {
         self.users.write().await.remove(nickname);
     }
 
-    pub async fn change_nick(&self, old_nick: &str, new_nick: &str) {
+    /// Moves a client to a new nick unless someone else holds it in any
+    /// case. Checked under the same lock as the move, so two clients
+    /// racing for a nick cannot both get it.
+    pub async fn change_nick(&self, old_nick: &str, new_nick: &str) -> Result<(), NickInUse> {
         let mut users = self.users.write().await;
+        if users
+            .keys()
+            .any(|user| user.eq_ignore_ascii_case(new_nick) && user != old_nick)
+        {
+            return Err(NickInUse);
+        }
         if let Some(client) = users.remove(old_nick) {
             users.insert(new_nick.to_string(), client);
         }
+        Ok(())
+    }
+
+    /// Everyone sharing at least one channel with `nickname`, excluding
+    /// the user themselves.
+    pub async fn channel_peers(&self, nickname: &str) -> Vec<Arc<RwLock<Client>>> {
+        let mut peers = HashMap::new();
+        for channel in self.channels.read().await.values() {
+            let channel = channel.read().await;
+            if !channel.users.contains_key(nickname) {
+                continue;
+            }
+            for (user, handle) in channel.users.iter() {
+                if user != nickname {
+                    peers.insert(user.clone(), handle.clone());
+                }
+            }
+        }
+        peers.into_values().collect()
+    }
+
+    /// The configuration currently in force.
+    pub fn config(&self) -> Arc<ServerConfig> {
+        self.config.read().unwrap().clone()
+    }
+
+    pub fn motd(&self) -> Arc<Vec<String>> {
+        self.motd.read().unwrap().clone()
+    }
+
+    /// Reloads the configuration file without dropping anyone. Everything
+    /// is loaded and checked before anything is replaced, so a bad config
+    /// leaves the running one in place. Connections keep the class they
+    /// were admitted under.
+    pub async fn rehash(self: &Arc<Self>) -> Result<(), String> {
+        let mut listeners = self.listeners.lock().await;
+
+        let config = get_configuration().map_err(|e| e.to_string())?;
+        let motd = config.read_motd().map_err(|e| e.to_string())?;
+        let certified_key = match (&config.tls, &self.certificates) {
+            (Some(tls_config), Some(_)) => {
+                let key = CertificateStore::read(tls_config)
+                    .map_err(|e| format!("TLS certificate: {}", e))?;
+                Some(key)
+            }
+            (Some(_), None) => return Err("TLS cannot be enabled without a restart".to_string()),
+            (None, _) => None,
+        };
+        let acceptor = self
+            .certificates
+            .clone()
+            .map(tls::acceptor)
+            .transpose()
+            .map_err(|e| format!("TLS: {}", e))?;
+        let mut bound = vec![];
+        for listener_config in &config.listeners {
+            if listeners.contains_key(&listener_config.bind_address()) {
+                continue;
+            }
+            let listener = Listener::bind(listener_config.clone())
+                .await
+                .map_err(|e| format!("Failed to bind to {}: {}", listener_config.name(), e))?;
+            bound.push(listener);
+        }
+
+        if let (Some(certificates), Some(certified_key)) = (&self.certificates, certified_key) {
+            certificates.replace(certified_key);
+        }
+        self.history.write().await.set_limits(&config.history);
+        self.accounts.write().await.set_limits(&config.accounts);
+        listeners.retain(|bind_address, sender| {
+            match config
+                .listeners
+                .iter()
+                .find(|listener| &listener.bind_address() == bind_address)
+            {
+                Some(listener_config) => {
+                    sender.send_replace(listener_config.clone());
+                    true
+                }
+                None => {
+                    tracing::info!("No longer listening on {}", bind_address);
+                    false
+                }
+            }
+        });
+        for listener in bound {
+            let bind_address = listener.config.bind_address();
+            let accept_loop = spawn_accept_loop(listener, acceptor.clone(), self.clone());
+            listeners.insert(bind_address, accept_loop);
+        }
+        *self.motd.write().unwrap() = Arc::new(motd);
+        let config = Arc::new(config);
+        *self.config.write().unwrap() = config.clone();
+
+        let handles = self
+            .users
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for handle in handles {
+            oper::refresh(&mut *handle.write().await, &config);
+        }
+        tracing::info!("Configuration reloaded");
+        Ok(())
+    }
+
+    /// Closes every connection, then exits once they have had a moment to
+    /// receive their ERROR.
+    pub async fn shutdown(&self, reason: &str) {
+        let handles = self
+            .users
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.read().await.sender.close(reason);
+        }
+        tokio::spawn(async {
+            tokio::time::sleep(CLOSE_TIMEOUT).await;
+            std::process::exit(0);
+        });
+    }
+
+    /// Forgets a channel once its last member leaves, unless it is
+    /// registered and should keep its state.
+    pub async fn remove_channel_if_empty(&self, name: &str) {
+        if self.channel_registry.read().await.is_registered(name) {
+            return;
+        }
+        let mut channels = self.channels.write().await;
+        let is_empty = match channels.get(name) {
+            Some(channel) => channel.read().await.users.is_empty(),
+            None => false,
+        };
+        if is_empty {
+            tracing::debug!("Removing empty channel {}", name);
+            channels.remove(name);
+        }
     }
 }
 
+/// The nick asked for is held by another user.
+#[derive(Debug)]
+pub struct NickInUse;
+
 #[derive(Debug)]
 pub struct ClientHandle {
     pub sender: mpsc::UnboundedSender<String>,
@@ -38,84 +236,625 @@ pub struct ClientHandle {
 
 pub type SharedServerState = Arc<ServerState>;
 
-#[instrument]
-pub async fn run(listener: TcpListener) -> Result<(), Box<dyn std::error::Error>> {
+/// What is known about a connection before its first line is read.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    pub ip: IpAddr,
+    /// Name of the listener the connection came in on
+    pub listener: String,
+    pub secure: bool,
+    pub certfp: Option<String>,
+    pub class: ClassConfig,
+}
+
+#[instrument(skip(config))]
+pub async fn run(
+    listeners: Vec<Listener>,
+    config: ServerConfig,
+    snomask_events: SnomaskEvents,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let certificates = match &config.tls {
+        Some(tls_config) => Some(Arc::new(CertificateStore::load(tls_config)?)),
+        None => None,
+    };
+
     let server_state = Arc::new(ServerState {
         users: RwLock::new(HashMap::new()),
         channels: RwLock::new(HashMap::new()),
+        history: RwLock::new(MessageHistory::load(&config.history)?),
+        accounts: RwLock::new(AccountStore::load(&config.accounts)?),
+        channel_registry: RwLock::new(ChannelRegistry::load(&config.channels)?),
+        bans: RwLock::new(BanStore::load(&config.bans)?),
+        certificates,
+        connections: Arc::new(ConnectionCounts::default()),
+        started_at: chrono::Utc::now()
+            .format("%a %b %e %Y at %H:%M:%S UTC")
+            .to_string(),
+        motd: std::sync::RwLock::new(Arc::new(config.read_motd()?)),
+        config: std::sync::RwLock::new(Arc::new(config)),
+        listeners: Mutex::new(HashMap::new()),
     });
 
-    loop {
-        let (socket, addr) = listener.accept().await.unwrap();
-        tracing::info!("Accepted connection from: {}", addr);
+    tokio::spawn(snomask::deliver(server_state.clone(), snomask_events));
 
-        let state_clone = server_state.clone();
+    let acceptor = server_state
+        .certificates
+        .clone()
+        .map(tls::acceptor)
+        .transpose()?;
+    {
+        let mut accept_loops = server_state.listeners.lock().await;
+        for listener in listeners {
+            let bind_address = listener.config.bind_address();
+            let accept_loop = spawn_accept_loop(listener, acceptor.clone(), server_state.clone());
+            accept_loops.insert(bind_address, accept_loop);
+        }
+    }
 
-        tokio::spawn(async move {
-            let _ = handle_client(socket, state_clone).await;
-        });
+    let mut hangup = signal(SignalKind::hangup())?;
+    while hangup.recv().await.is_some() {
+        tracing::info!("Received SIGHUP, rehashing");
+        if let Err(e) = server_state.rehash().await {
+            tracing::error!("Rehash failed, keeping the running configuration: {}", e);
+        }
     }
+    Ok(())
 }
 
-#[tracing::instrument(name = "Handling client connection", skip(socket))]
+/// Accepts connections on `listener` until the returned sender is dropped.
+/// Each connection is set up under the listener definition current when it
+/// arrives, so a rehash can change settings without rebinding.
+fn spawn_accept_loop(
+    listener: Listener,
+    acceptor: Option<TlsAcceptor>,
+    server_state: SharedServerState,
+) -> watch::Sender<ListenerConfig> {
+    tracing::info!("Listening on {}", listener.config.name());
+    let (config_tx, mut config_rx) = watch::channel(listener.config.clone());
+    tokio::spawn(async move {
+        loop {
+            let (stream, ip) = tokio::select! {
+                changed = config_rx.changed() => {
+                    if changed.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+                connection = listener.accept() => match connection {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        tracing::error!("Failed to accept connection: {}", e);
+                        continue;
+                    }
+                },
+            };
+            if let Some(ban) = server_state.bans.read().await.dline_for(&ip) {
+                tracing::info!(
+                    snomask = %Snomask::Ban,
+                    "Refusing D-lined {}: {}",
+                    ip,
+                    ban.reason
+                );
+                continue;
+            }
+            let config = config_rx.borrow().clone();
+            tracing::info!("Accepted connection from: {} on {}", ip, config.name());
+
+            tokio::spawn(accept_connection(
+                stream,
+                ip,
+                config,
+                acceptor.clone(),
+                server_state.clone(),
+            ));
+        }
+    });
+    config_tx
+}
+
+/// Takes a freshly accepted socket through the PROXY header, TLS and
+/// WebSocket handshakes its listener calls for, then serves the client.
+async fn accept_connection(
+    mut stream: ClientStream,
+    ip: IpAddr,
+    config: ListenerConfig,
+    acceptor: Option<TlsAcceptor>,
+    server_state: SharedServerState,
+) {
+    let mut connection = ConnectionInfo {
+        ip,
+        listener: config.name(),
+        secure: false,
+        certfp: None,
+        class: ClassConfig::default(),
+    };
+
+    if config.proxy {
+        if !config.trusted_proxies.iter().any(|cidr| cidr.contains(&ip)) {
+            tracing::warn!("Rejecting PROXY connection from untrusted {}", ip);
+            return;
+        }
+        let header = tokio::time::timeout(PROXY_TIMEOUT, proxy::read_header(&mut stream)).await;
+        match header {
+            Ok(Ok(Some(client_ip))) => {
+                tracing::info!("{} is proxying for {}", ip, client_ip);
+                if let Some(ban) = server_state.bans.read().await.dline_for(&client_ip) {
+                    tracing::info!(
+                        snomask = %Snomask::Ban,
+                        "Refusing D-lined {}: {}",
+                        client_ip,
+                        ban.reason
+                    );
+                    return;
+                }
+                connection.ip = client_ip;
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                tracing::info!("Bad PROXY header from {}: {}", ip, e);
+                return;
+            }
+            Err(_) => {
+                tracing::info!("Timed out waiting for PROXY header from {}", ip);
+                return;
+            }
+        }
+    }
+
+    if let Some(acceptor) = acceptor.as_ref().filter(|_| config.tls) {
+        //the connection is not counted against its class until it is
+        //through the handshakes, so they must not be left to hang
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.upgrade(acceptor)).await {
+            Ok(Ok((tls_stream, certfp))) => {
+                stream = tls_stream;
+                connection.secure = true;
+                connection.certfp = certfp;
+            }
+            Ok(Err(e)) => {
+                tracing::info!("TLS handshake with {} failed: {}", connection.ip, e);
+                return;
+            }
+            Err(_) => {
+                tracing::info!("Timed out waiting for TLS handshake from {}", connection.ip);
+                return;
+            }
+        }
+    }
+
+    connection.class = server_state
+        .config()
+        .class_for(&connection.ip, &connection.listener);
+    let max_line = connection.class.recvq;
+    let mut transport = if config.websocket {
+        let handshake = Transport::websocket(stream, &config.allowed_origins, max_line);
+        match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(transport)) => transport,
+            Ok(Err(e)) => {
+                tracing::info!("WebSocket handshake with {} failed: {}", connection.ip, e);
+                return;
+            }
+            Err(_) => {
+                tracing::info!(
+                    "Timed out waiting for WebSocket handshake from {}",
+                    connection.ip
+                );
+                return;
+            }
+        }
+    } else {
+        Transport::stream(stream, max_line)
+    };
+
+    //a gateway carries many users, each counted by their own address once
+    //WEBIRC names it, so only the class as a whole limits the gateway
+    let mut limits = connection.class.clone();
+    if server_state.config().is_gateway(&connection.ip) {
+        limits.max_per_ip = None;
+        limits.max_per_cidr = None;
+    }
+    let Some(slot) = server_state.connections.admit(&limits, connection.ip) else {
+        tracing::warn!(
+            "Too many connections from {} in class {}",
+            connection.ip,
+            connection.class.name
+        );
+        let _ = transport
+            .write_line("ERROR :Too many connections from your host\r\n")
+            .await;
+        return;
+    };
+    let _ = handle_client(transport, connection, slot, acceptor, server_state).await;
+}
+
+/// The RPL_ISUPPORT tokens describing the server's limits and modes.
+fn isupport(config: &ServerConfig) -> String {
+    format!(
+        "NETWORK={} CASEMAPPING=ascii CHANTYPES=# PREFIX=(ov)@+ CHANMODES=b,,,{} \
+         NICKLEN={} CHANNELLEN={} TOPICLEN={}",
+        //token values cannot hold spaces
+        config.network.replace(' ', "\\x20"),
+        CHANNEL_MODES,
+        config.limits.nick_length,
+        config.limits.channel_length,
+        config.limits.topic_length
+    )
+}
+
+/// Runs a command, routing its replies through a labeled response when the
+/// client tagged it with `label` and negotiated `labeled-response`.
+async fn handle_command(
+    command: &Command,
+    line: &str,
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let label = {
+        let active_session = session.read().await;
+        let (tags, _) = split_tags(line);
+        tags.into_iter()
+            .find(|(key, _)| key == "label")
+            .map(|(_, value)| value)
+            .filter(|_| {
+                active_session
+                    .capabilities
+                    .contains(&Capability::LabeledResponse)
+            })
+    };
+    let Some(label) = label else {
+        return command.handle(session, server_state).await;
+    };
+
+    //collect what the command sends back to this client
+    let sender = session.read().await.sender.clone();
+    let (result, responses) = sender
+        .capture(async {
+            //the boxed error is not Send, so it cannot be held across awaits
+            command
+                .handle(session, server_state)
+                .await
+                .map_err(|e| e.to_string())
+        })
+        .await;
+    let active_session = session.read().await;
+    for response in batch::label_responses(&active_session.capabilities, &label, responses) {
+        let _ = active_session.sender.send(response);
+    }
+    Ok(result?)
+}
+
+#[tracing::instrument(
+    name = "Handling client connection",
+    skip(transport, slot, acceptor, server_state)
+)]
 async fn handle_client(
-    socket: TcpStream,
+    mut transport: Transport,
+    mut connection: ConnectionInfo,
+    mut slot: ConnectionSlot,
+    acceptor: Option<TlsAcceptor>,
     server_state: SharedServerState,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let (reader, mut writer) = socket.into_split();
-    let mut reader = BufReader::new(reader).lines();
-
-    let (client_tx, mut client_rx) = mpsc::unbounded_channel::<String>();
+    //reads and writes share one task so STARTTLS can take the transport back
+    let (client_tx, mut client_rx) = sendq::channel(connection.class.sendq);
+    let mut queue = client_tx.clone();
 
     let nickname = format!("guest{}", rand::thread_rng().gen_range(1..=9999));
-    let session: Arc<RwLock<Client>> = Arc::new(RwLock::new(Client::new(nickname, client_tx)));
+    let mut client = Client::new(nickname, client_tx);
+    client.set_address(connection.ip);
+    client.listener = connection.listener.clone();
+    client.secure = connection.secure;
+    client.certfp = connection.certfp;
+    let session: Arc<RwLock<Client>> = Arc::new(RwLock::new(client));
     let nickname = session.read().await.nick.clone().unwrap();
     server_state.add_client(nickname.clone(), &session).await;
 
-    tokio::spawn(async move {
-        while let Some(msg) = client_rx.recv().await {
-            let _ = writer.write_all(msg.as_bytes()).await;
+    let mut flood = FloodControl::new(&connection.class);
+    let mut keepalive = Keepalive::new(&connection.class);
+    let mut registered = false;
+    //set when the server closes the link rather than the client
+    let mut close_reason = None;
+    let transport = loop {
+        //queued lines are handled before more are read, so input already
+        //within the rate limit is not lost when the client disconnects
+        let line = tokio::select! {
+            biased;
+            reason = queue.closed() => {
+                close_reason = Some(reason);
+                break Some(transport);
+            }
+            message = client_rx.recv() => {
+                let Some(message) = message else { break Some(transport) };
+                //a stalled client must not keep the queue from being closed
+                tokio::select! {
+                    result = transport.write_line(&message) => {
+                        if result.is_err() {
+                            break Some(transport);
+                        }
+                    }
+                    reason = queue.closed() => {
+                        close_reason = Some(reason);
+                        break Some(transport);
+                    }
+                }
+                continue;
+            }
+            expiry = keepalive.expired() => match expiry {
+                Expiry::Ping(token) => {
+                    let _ = queue.send(format!("PING :{}\r\n", token));
+                    continue;
+                }
+                Expiry::Disconnect(reason) => {
+                    close_reason = Some(CloseReason::new(reason));
+                    break Some(transport);
+                }
+            },
+            line = flood.next() => line,
+            line = transport.read_line() => match line {
+                Ok(Some(line)) => {
+                    keepalive.active();
+                    if !flood.push(line) {
+                        tracing::warn!(
+                            snomask = %Snomask::Flood,
+                            "Excess flood from {}",
+                            connection.ip
+                        );
+                        close_reason = Some(CloseReason::new("Excess Flood"));
+                        break Some(transport);
+                    }
+                    continue;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    tracing::warn!(
+                        snomask = %Snomask::Flood,
+                        "Excess flood from {}: line over RecvQ",
+                        connection.ip
+                    );
+                    close_reason = Some(CloseReason::new("Excess Flood"));
+                    break Some(transport);
+                }
+                _ => break Some(transport),
+            },
+        };
+        let command = Command::parse(&line);
+        keepalive.command(&command);
+
+        if let Command::STARTTLS = command {
+            let queued_input = flood.has_queued() || transport.has_buffered_input();
+            match starttls_error(&session, acceptor.is_some(), &transport, queued_input).await {
+                Some(reason) => {
+                    let params = ResponseParams::new(nickname_of(&session).await).message(reason);
+                    let _ = session
+                        .read()
+                        .await
+                        .sender
+                        .send(ResponseCode::ERR_STARTTLS.message(params));
+                }
+                None => {
+                    match start_tls(
+                        transport,
+                        &mut client_rx,
+                        &session,
+                        acceptor.as_ref().unwrap(),
+                    )
+                    .await
+                    {
+                        Ok(upgraded) => {
+                            tracing::info!("{} upgraded to TLS", connection.ip);
+                            transport = upgraded;
+                            //pings must go through the channel now being read
+                            queue = session.read().await.sender.clone();
+                        }
+                        Err(e) => {
+                            tracing::info!("STARTTLS with {} failed: {}", connection.ip, e);
+                            break None;
+                        }
+                    }
+                }
+            }
+            continue;
         }
-    });
 
-    while let Ok(Some(line)) = reader.next_line().await {
-        let command = Command::parse(&line);
-        
-        match command.handle(&session, &server_state).await {
-            Ok(false) => break,
-            Ok(true) => continue,
+        match handle_command(&command, &line, &session, &server_state).await {
+            Ok(false) => break Some(transport),
+            Ok(true) => {}
             Err(e) => {
                 tracing::error!("Error handling command: {:?}", e);
-                break;
+                break Some(transport);
             }
         }
+        //bans are checked once the user and host are final
+        if !registered && keepalive.is_registered() {
+            registered = true;
+            if !bans::drop_if_banned(&session, &server_state).await {
+                let mut active_session = session.write().await;
+                if let ClientState::Unregistered = active_session.state {
+                    active_session.state = ClientState::Registered;
+                }
+                tracing::info!(
+                    snomask = %Snomask::Connect,
+                    "Client connecting: {} [{}] on {}",
+                    active_session.mask(),
+                    active_session.ip,
+                    active_session.listener
+                );
+                let config = server_state.config();
+                let params = || {
+                    ResponseParams::new(active_session.nick.clone().unwrap())
+                        .server(config.server_name.clone())
+                };
+                for reply in [
+                    ResponseCode::RPL_WELCOME.message(params().message(config.network.clone())),
+                    ResponseCode::RPL_YOURHOST.message(params()),
+                    ResponseCode::RPL_CREATED
+                        .message(params().date(server_state.started_at.clone())),
+                    ResponseCode::RPL_MYINFO.message(params()),
+                    ResponseCode::RPL_ISUPPORT.message(params().message(isupport(&config))),
+                ] {
+                    let _ = active_session.sender.send(reply);
+                }
+                drop(active_session);
+                command::send_motd(&session, &server_state).await;
+            }
+        }
+        //a gateway's user is counted and limited by their own address, not
+        //against the gateway's
+        if let Command::WEBIRC(..) = command {
+            let ip = {
+                let active_session = session.read().await;
+                Some(active_session.ip).filter(|_| active_session.gateway.is_some())
+            };
+            if let Some(ip) = ip.filter(|ip| *ip != connection.ip) {
+                let class = server_state.config().class_for(&ip, &connection.listener);
+                //the gateway's slot is released only once the user's is held
+                let Some(user_slot) = server_state.connections.admit(&class, ip) else {
+                    tracing::warn!("Too many connections from {} in class {}", ip, class.name);
+                    close_reason = Some(CloseReason::new("Too many connections from your host"));
+                    break Some(transport);
+                };
+                drop(std::mem::replace(&mut slot, user_slot));
+                flood.set_class(&class);
+                keepalive.set_class(&class);
+                queue.set_limit(class.sendq);
+                transport.set_max_line(class.recvq);
+                connection.ip = ip;
+                connection.class = class;
+            }
+        }
+        //opers are trusted not to flood
+        if let Command::OPER(..) = command {
+            if session.read().await.oper.is_some() {
+                flood.exempt();
+            }
+        }
+    };
+
+    if let Some(mut transport) = transport {
+        if let Some(reason) = &close_reason {
+            tracing::info!("Closing link to {}: {}", connection.ip, reason.quit);
+        }
+        //deliver anything still queued, such as the reply to QUIT, without
+        //letting a stalled client hold up cleanup
+        let flush = async {
+            while let Some(message) = client_rx.try_recv() {
+                transport.write_line(&message).await?;
+            }
+            if let Some(reason) = &close_reason {
+                let error = format!("ERROR :{}\r\n", reason.error);
+                transport.write_line(&error).await?;
+            }
+            std::io::Result::Ok(())
+        };
+        let _ = tokio::time::timeout(CLOSE_TIMEOUT, flush).await;
     }
 
     //cleanup client state and remove them from any channels
-    let nickname = session.read().await.nick.clone().unwrap();
-    tracing::info!("Client {} disconnected", nickname);
-    let client = server_state
-        .users
-        .write()
-        .await
-        .remove(&nickname.clone())
-        .unwrap();
-    let client: tokio::sync::RwLockReadGuard<'_, Client> = client.read().await;
+    let (nickname, mask) = {
+        let active_session = session.read().await;
+        (active_session.nick.clone().unwrap(), active_session.mask())
+    };
+    let quit_reason = close_reason
+        .as_ref()
+        .map_or("Client exit STUB", |reason| reason.quit.as_str());
+    if registered {
+        tracing::info!(
+            snomask = %Snomask::Quit,
+            "Client exiting: {} ({})",
+            mask,
+            quit_reason
+        );
+    } else {
+        tracing::info!("Client {} disconnected", nickname);
+    }
+    server_state.remove_client(&nickname).await;
+
+    //Send Quit message once to everyone sharing a channel with the user
+    let quit_msg = format!(":{} QUIT :{}\r\n", nickname, quit_reason);
+    let tags = MessageTags::new();
+    for peer in server_state.channel_peers(&nickname).await {
+        peer.read().await.send_tagged(&tags, &quit_msg);
+    }
+
     tracing::info!("Removing client from channels");
+    let mut left_channels = vec![];
     for channel in server_state.channels.read().await.values() {
         let mut channel = channel.write().await;
-        channel.users.remove(&client.nick.clone().unwrap());
-
-        //Send Quit message to users in the channel
-        let quit_msg = format!(
-            ":{} QUIT :Client exit STUB\r\n",
-            client.nick.clone().unwrap()
-        );
-        for user in channel.users.values() {
-            user.read().await.sender.send(quit_msg.clone()).unwrap();
+        if !channel.users.contains_key(&nickname) {
+            continue;
         }
+        channel.remove_user(&nickname);
+        left_channels.push(channel.name.clone());
+    }
+    for channel in left_channels {
+        server_state.remove_channel_if_empty(&channel).await;
     }
     tracing::info!("Client cleanup complete");
 
     Ok(())
 }
+
+async fn nickname_of(session: &Arc<RwLock<Client>>) -> String {
+    session.read().await.nick.clone().unwrap()
+}
+
+/// Why a STARTTLS request cannot be honoured, if it can't.
+async fn starttls_error(
+    session: &Arc<RwLock<Client>>,
+    tls_available: bool,
+    transport: &Transport,
+    queued_input: bool,
+) -> Option<&'static str> {
+    let active_session = session.read().await;
+    if active_session.secure {
+        Some("Already using TLS")
+    } else if !tls_available {
+        Some("TLS is not configured")
+    } else if !transport.supports_starttls() {
+        Some("Not supported on this transport")
+    } else if matches!(active_session.state, ClientState::Registered) {
+        Some("Already registered")
+    } else if queued_input {
+        //plaintext sent after STARTTLS could be injected by a third party
+        Some("Data received after STARTTLS")
+    } else {
+        None
+    }
+}
+
+/// Flushes everything queued for the client, confirms STARTTLS and performs
+/// the handshake. Replies queued from here on go through a fresh channel.
+async fn start_tls(
+    mut transport: Transport,
+    client_rx: &mut SendQueueReceiver,
+    session: &Arc<RwLock<Client>>,
+    acceptor: &TlsAcceptor,
+) -> std::io::Result<Transport> {
+    //the session is only held while the sender is swapped, as writing to
+    //the client can take as long as the client likes
+    let (nickname, mut plaintext_rx) = {
+        let mut active_session = session.write().await;
+        let (client_tx, new_rx) = active_session.sender.redirect();
+        active_session.sender = client_tx;
+        (
+            active_session.nick.clone().unwrap(),
+            std::mem::replace(client_rx, new_rx),
+        )
+    };
+    let mut pending = vec![];
+    while let Some(message) = plaintext_rx.try_recv() {
+        pending.push(message);
+    }
+    pending.push(ResponseCode::RPL_STARTTLS.message(ResponseParams::new(nickname)));
+
+    let handshake = async {
+        for message in pending {
+            transport.write_line(&message).await?;
+        }
+        transport.upgrade(acceptor).await
+    };
+    let (transport, certfp) = tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out"))??;
+    let mut active_session = session.write().await;
+    active_session.secure = true;
+    active_session.certfp = certfp;
+    Ok(transport)
+}
@@ -0,0 +1,90 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use super::command::Command;
+use crate::configuration::ClassConfig;
+
+/// Something the connection has to act on because the client went quiet.
+#[derive(Debug)]
+pub enum Expiry {
+    /// Send the client a PING carrying this token
+    Ping(String),
+    /// Close the link with this reason
+    Disconnect(String),
+}
+
+/// Tracks when a client was last heard from, so idle clients are pinged,
+/// dead ones dropped and ones that never register do not linger.
+#[derive(Debug)]
+pub struct Keepalive {
+    ping_frequency: Duration,
+    registration_deadline: Instant,
+    last_active: Instant,
+    ping_sent: Option<Instant>,
+    sent_nick: bool,
+    sent_user: bool,
+    /// CAP negotiation holds registration open until CAP END
+    negotiating: bool,
+}
+
+impl Keepalive {
+    pub fn new(class: &ClassConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            ping_frequency: Duration::from_secs(class.ping_frequency),
+            registration_deadline: now + Duration::from_secs(class.registration_timeout),
+            last_active: now,
+            ping_sent: None,
+            sent_nick: false,
+            sent_user: false,
+            negotiating: false,
+        }
+    }
+
+    /// Pings at the frequency of the class the client was moved to.
+    pub fn set_class(&mut self, class: &ClassConfig) {
+        self.ping_frequency = Duration::from_secs(class.ping_frequency);
+    }
+
+    /// Records that the client sent something; any line shows it is alive.
+    pub fn active(&mut self) {
+        self.last_active = Instant::now();
+        self.ping_sent = None;
+    }
+
+    /// Follows the client through registration.
+    pub fn command(&mut self, command: &Command) {
+        match command {
+            Command::NICK(_) => self.sent_nick = true,
+            Command::USER(..) => self.sent_user = true,
+            Command::CapLs | Command::CapReq(_) => self.negotiating = true,
+            Command::CapEnd => self.negotiating = false,
+            _ => {}
+        }
+    }
+
+    pub fn is_registered(&self) -> bool {
+        self.sent_nick && self.sent_user && !self.negotiating
+    }
+
+    /// Waits for the client's next deadline to pass. Cancel safe.
+    pub async fn expired(&mut self) -> Expiry {
+        let ping_deadline = match self.ping_sent {
+            Some(sent) => sent + self.ping_frequency,
+            None => self.last_active + self.ping_frequency,
+        };
+        if !self.is_registered() && self.registration_deadline <= ping_deadline {
+            tokio::time::sleep_until(self.registration_deadline).await;
+            return Expiry::Disconnect("Registration timed out".to_string());
+        }
+
+        tokio::time::sleep_until(ping_deadline).await;
+        if self.ping_sent.is_some() {
+            let silent = self.last_active.elapsed().as_secs();
+            return Expiry::Disconnect(format!("Ping timeout: {} seconds", silent));
+        }
+        self.ping_sent = Some(Instant::now());
+        Expiry::Ping(chrono::Utc::now().timestamp().to_string())
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::fs::FileTypeExt;
+
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+use super::transport::ClientStream;
+use crate::configuration::ListenerConfig;
+
+#[derive(Debug)]
+pub enum ListenerSocket {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// A bound socket along with the definition it was created from.
+#[derive(Debug)]
+pub struct Listener {
+    pub socket: ListenerSocket,
+    pub config: ListenerConfig,
+}
+
+impl Listener {
+    pub async fn bind(config: ListenerConfig) -> io::Result<Self> {
+        let socket = if config.is_unix() {
+            remove_stale_socket(&config.address).await?;
+            ListenerSocket::Unix(UnixListener::bind(&config.address)?)
+        } else {
+            let port = config.port.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "TCP listeners need a port")
+            })?;
+            ListenerSocket::Tcp(TcpListener::bind((config.address.as_str(), port)).await?)
+        };
+        Ok(Self { socket, config })
+    }
+
+    /// Accepts the next connection. Unix-socket clients are local, so they
+    /// are treated as coming from the loopback address.
+    pub async fn accept(&self) -> io::Result<(ClientStream, IpAddr)> {
+        match &self.socket {
+            ListenerSocket::Tcp(listener) => {
+                let (socket, addr) = listener.accept().await?;
+                Ok((ClientStream::Plain(socket), addr.ip()))
+            }
+            ListenerSocket::Unix(listener) => {
+                let (socket, _) = listener.accept().await?;
+                Ok((ClientStream::Unix(socket), IpAddr::V4(Ipv4Addr::LOCALHOST)))
+            }
+        }
+    }
+}
+
+/// Removes a socket file left behind by an earlier run, which would fail
+/// the bind. Anything that is not a socket, or a socket something is
+/// still listening on, is left in place and reported instead.
+async fn remove_stale_socket(path: &str) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path),
+        ));
+    }
+    if UnixStream::connect(path).await.is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("{} is in use by another process", path),
+        ));
+    }
+    std::fs::remove_file(path)
+}
@@ -1,5 +1,24 @@
+pub mod accounts;
+pub mod bans;
+pub mod batch;
 pub mod channel;
+pub mod channel_registry;
 pub mod client;
 pub mod command;
+pub mod connections;
+pub mod flood;
+pub mod history;
+#[allow(clippy::module_inception)]
 pub mod ircd;
+pub mod keepalive;
+pub mod listener;
+pub mod oper;
+pub mod proxy;
 pub mod response;
+pub mod sasl;
+pub mod sendq;
+pub mod services;
+pub mod snomask;
+pub mod tags;
+pub mod tls;
+pub mod transport;
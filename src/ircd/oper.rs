@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use secrecy::ExposeSecret;
+use tokio::sync::RwLock;
+
+use super::client::Client;
+use super::ircd::SharedServerState;
+use super::response::{ResponseCode, ResponseParams};
+use crate::configuration::{OperConfig, Privilege, ServerConfig};
+use crate::helpers::{mask_matches, verify_password};
+
+/// What a user who has used OPER may do.
+#[derive(Debug, Clone)]
+pub struct Oper {
+    /// Name of the oper block they authenticated against
+    pub name: String,
+    pub class: String,
+    pub privileges: HashSet<Privilege>,
+}
+
+/// Checks that the user holds `privilege`, telling them why not when they
+/// don't.
+pub async fn require(session: &Arc<RwLock<Client>>, privilege: Privilege) -> bool {
+    let active_session = session.read().await;
+    let params = ResponseParams::new(active_session.nick.clone().unwrap());
+    let reply = match &active_session.oper {
+        Some(oper) if oper.privileges.contains(&privilege) => return true,
+        Some(_) => ResponseCode::ERR_NOPRIVS.message(params.message(privilege.name())),
+        None => ResponseCode::ERR_NOPRIVILEGES.message(params),
+    };
+    let _ = active_session.sender.send(reply);
+    false
+}
+
+/// Handles OPER, granting the privileges of the named oper block when the
+/// user's host matches it along with whichever of the certificate and
+/// password it requires.
+pub async fn oper_up(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    name: &str,
+    password: &str,
+) {
+    let (nickname, masks, certfp) = {
+        let active_session = session.read().await;
+        let user = active_session.user.clone().unwrap_or_default();
+        (
+            active_session.nick.clone().unwrap(),
+            [
+                format!("{}@{}", user, active_session.host),
+                format!("{}@{}", user, active_session.ip),
+            ],
+            active_session.certfp.clone(),
+        )
+    };
+    let params = ResponseParams::new(nickname.clone());
+    let config = server_state.config();
+    let block = config.opers.iter().find(|block| {
+        block.name == name
+            && block
+                .hosts
+                .iter()
+                .any(|host| masks.iter().any(|mask| mask_matches(host, mask)))
+    });
+    let Some(block) = block else {
+        tracing::warn!("Failed OPER as {} by {}: no matching block", name, nickname);
+        let reply = ResponseCode::ERR_NOOPERHOST.message(params);
+        let _ = session.read().await.sender.send(reply);
+        return;
+    };
+
+    //checked without holding the session, as verifying the password is slow
+    if !credentials_match(block, certfp.as_deref(), password).await {
+        tracing::warn!("Failed OPER as {} by {}: bad credentials", name, nickname);
+        let reply = ResponseCode::ERR_PASSWDMISMATCH.message(params);
+        let _ = session.read().await.sender.send(reply);
+        return;
+    }
+
+    let Some(class) = config
+        .oper_classes
+        .iter()
+        .find(|class| class.name == block.class)
+    else {
+        tracing::error!(
+            "Oper block {} names unknown class {}",
+            block.name,
+            block.class
+        );
+        let reply = ResponseCode::ERR_NOOPERHOST.message(params);
+        let _ = session.read().await.sender.send(reply);
+        return;
+    };
+
+    let mut active_session = session.write().await;
+    tracing::info!("{} is now an operator as {}", nickname, block.name);
+    active_session.modes.insert('o');
+    active_session.oper = Some(Oper {
+        name: block.name.clone(),
+        class: class.name.clone(),
+        privileges: class.privileges.iter().copied().collect(),
+    });
+    let _ = active_session
+        .sender
+        .send(ResponseCode::RPL_YOUREOPER.message(params));
+    let _ = active_session
+        .sender
+        .send(format!(":{} MODE {} :+o\r\n", nickname, nickname));
+}
+
+/// Brings a connected oper's privileges in line with a reloaded config,
+/// taking away their oper status when their block or its class is gone.
+pub fn refresh(client: &mut Client, config: &ServerConfig) {
+    let Some(name) = client.oper.as_ref().map(|oper| oper.name.clone()) else {
+        return;
+    };
+    let class = config
+        .opers
+        .iter()
+        .find(|block| block.name == name)
+        .and_then(|block| {
+            config
+                .oper_classes
+                .iter()
+                .find(|class| class.name == block.class)
+        });
+    if let Some(class) = class {
+        client.oper = Some(Oper {
+            name,
+            class: class.name.clone(),
+            privileges: class.privileges.iter().copied().collect(),
+        });
+        return;
+    }
+
+    let nickname = client.nick.clone().unwrap();
+    tracing::info!(
+        "{} is no longer an operator: oper block {} was removed",
+        nickname,
+        name
+    );
+    client.oper = None;
+    client.modes.remove(&'o');
+    let mut modes = "-o".to_string();
+    //server notices are for opers only
+    if client.modes.remove(&'s') {
+        modes.push('s');
+    }
+    client.snomask.clear();
+    let _ = client
+        .sender
+        .send(format!(":{} MODE {} :{}\r\n", nickname, nickname, modes));
+}
+
+/// Whether every credential the block asks for was given; a block with a
+/// certificate and no password is opened by the certificate alone.
+async fn credentials_match(block: &OperConfig, certfp: Option<&str>, password: &str) -> bool {
+    if block.password.is_none() && block.certfp.is_none() {
+        return false;
+    }
+    let certfp_matches = block
+        .certfp
+        .as_ref()
+        .is_none_or(|required| certfp.is_some_and(|certfp| certfp.eq_ignore_ascii_case(required)));
+    if !certfp_matches {
+        return false;
+    }
+    match &block.password {
+        Some(hash) => verify_password(password, hash.expose_secret()).await,
+        None => true,
+    }
+}
@@ -0,0 +1,234 @@
+use std::io::{Error, ErrorKind, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol v1 or v2 header, returning the client address it
+/// carries. `None` means the header is valid but describes no client, as
+/// with health checks, and the connection's own address should be used.
+/// Reads only the header, so whatever follows is left on the stream.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<IpAddr>> {
+    let mut start = [0u8; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        read_v1(stream).await
+    } else if start == V2_SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid("missing PROXY header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<IpAddr>> {
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = String::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+
+    //PROXY <TCP4|TCP6|UNKNOWN> <source> <destination> <source port> <destination port>
+    let fields = line.trim_end().split(' ').collect::<Vec<_>>();
+    match fields.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, _, _] => source
+            .parse()
+            .map(Some)
+            .map_err(|_| invalid("bad source address in PROXY v1 header")),
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Option<IpAddr>> {
+    let mut header = [0u8; 16];
+    header[..5].copy_from_slice(&V2_SIGNATURE[..5]);
+    stream.read_exact(&mut header[5..]).await?;
+    if header[..12] != V2_SIGNATURE {
+        return Err(invalid("bad PROXY v2 signature"));
+    }
+    let version = header[12] >> 4;
+    let command = header[12] & 0x0f;
+    let family = header[13];
+    let length = u16::from_be_bytes([header[14], header[15]]) as usize;
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    //LOCAL connections come from the proxy itself
+    if command == 0x0 {
+        return Ok(None);
+    }
+    if command != 0x1 {
+        return Err(invalid("unknown PROXY v2 command"));
+    }
+    match family >> 4 {
+        //AF_INET: source address, destination address, ports
+        0x1 if length >= 12 => {
+            let octets: [u8; 4] = addresses[..4].try_into().unwrap();
+            Ok(Some(IpAddr::V4(Ipv4Addr::from(octets))))
+        }
+        //AF_INET6
+        0x2 if length >= 36 => {
+            let octets: [u8; 16] = addresses[..16].try_into().unwrap();
+            Ok(Some(IpAddr::V6(Ipv6Addr::from(octets))))
+        }
+        0x1 | 0x2 => Err(invalid("truncated PROXY v2 address block")),
+        //AF_UNSPEC and AF_UNIX carry no IP address
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A v2 header with the given version/command byte, family byte and
+    /// address block.
+    fn v2(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    async fn read(input: &[u8]) -> (Result<Option<IpAddr>>, Vec<u8>) {
+        let mut stream = input;
+        let result = read_header(&mut stream).await;
+        (result, stream.to_vec())
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4_gives_the_source_and_leaves_the_rest() {
+        let (result, rest) =
+            read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 6667\r\nNICK a\r\n").await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(rest, b"NICK a\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6_gives_the_source() {
+        let (result, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 6667\r\n").await;
+        assert_eq!(result.unwrap(), Some("2001:db8::1".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown_has_no_client() {
+        let (result, rest) = read(b"PROXY UNKNOWN\r\nNICK a\r\n").await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"NICK a\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_refuses_bad_addresses_and_missing_fields() {
+        let (result, _) = read(b"PROXY TCP4 192.0.2.300 198.51.100.1 1 2\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        let (result, _) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        let (result, _) = read(b"PROXY UDP4 192.0.2.1 198.51.100.1 1 2\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v1_stops_reading_at_the_length_limit() {
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.extend(std::iter::repeat_n(b'1', 200));
+        input.extend_from_slice(b"\r\n");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        assert_eq!(rest.len(), input.len() - V1_MAX_LENGTH);
+    }
+
+    #[tokio::test]
+    async fn v1_truncated_is_an_early_eof() {
+        let (result, _) = read(b"PROXY TCP4 192.0.2.1").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        let (result, _) = read(b"PRO").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn plain_irc_is_not_a_header() {
+        let (result, _) = read(b"NICK a\r\nUSER a 0 * :a\r\n").await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4_gives_the_source_and_leaves_the_rest() {
+        let mut input = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        input.extend_from_slice(b"NICK a\r\n");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(rest, b"NICK a\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_gives_the_source() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut addresses = source.octets().to_vec();
+        addresses.extend_from_slice(&[0; 20]);
+        let (result, _) = read(&v2(0x21, 0x21, &addresses)).await;
+        assert_eq!(result.unwrap(), Some(IpAddr::V6(source)));
+    }
+
+    #[tokio::test]
+    async fn v2_local_has_no_client_and_skips_its_addresses() {
+        let mut input = v2(0x20, 0x11, &[10, 0, 0, 1, 10, 0, 0, 2, 0, 1, 0, 2]);
+        input.extend_from_slice(b"NICK a\r\n");
+        let (result, rest) = read(&input).await;
+        assert_eq!(result.unwrap(), None);
+        assert_eq!(rest, b"NICK a\r\n");
+    }
+
+    #[tokio::test]
+    async fn v2_unix_and_unspec_have_no_client() {
+        let (result, _) = read(&v2(0x21, 0x31, &[0; 216])).await;
+        assert_eq!(result.unwrap(), None);
+        let (result, _) = read(&v2(0x21, 0x00, &[])).await;
+        assert_eq!(result.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_refuses_a_short_address_block() {
+        let (result, _) = read(&v2(0x21, 0x11, &[192, 0, 2, 1])).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        let (result, _) = read(&v2(0x21, 0x21, &[0; 12])).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn v2_truncated_is_an_early_eof() {
+        let input = v2(0x21, 0x11, &[192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2]);
+        let (result, _) = read(&input[..20]).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+        let (result, _) = read(&input[..10]).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn v2_refuses_other_versions_commands_and_signatures() {
+        let addresses = [192, 0, 2, 1, 198, 51, 100, 1, 0, 1, 0, 2];
+        let (result, _) = read(&v2(0x11, 0x11, &addresses)).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        let (result, _) = read(&v2(0x22, 0x11, &addresses)).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        let mut input = v2(0x21, 0x11, &addresses);
+        input[8] = b'X';
+        let (result, _) = read(&input).await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+    }
+}
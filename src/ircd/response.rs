@@ -1,3 +1,6 @@
+use super::channel::{CHANNEL_MODES, CHANNEL_PARAM_MODES};
+use super::client::USER_MODES;
+
 #[allow(non_camel_case_types, clippy::zero_prefixed_literal)]
 #[repr(u16)]
 #[derive(Debug, Clone, Copy)]
@@ -7,9 +10,13 @@ pub enum ResponseCode {
     RPL_CREATED = 003,
     RPL_MYINFO = 004,
     RPL_ISUPPORT = 005,
+    RPL_SNOMASKIS = 008,
     RPL_BOUNCE = 010,
     RPL_STATSCOMMANDS = 212,
     RPL_ENDOFSTATS = 219,
+    RPL_STATSKLINE = 216,
+    RPL_STATSPLINE = 220,
+    RPL_STATSDLINE = 225,
     RPL_UMODEIS = 221,
     RPL_STATSUPTIME = 242,
     RPL_LUSERCLIENT = 251,
@@ -144,9 +151,12 @@ impl From<ResponseCode> for u16 {
 pub struct ResponseParams {
     client: String,
     stub: String,
+    command: Option<String>,
     channel: Option<String>,
     nick: Option<String>,
+    user: Option<String>,
     host: Option<String>,
+    account: Option<String>,
     message: Option<String>,
     server: Option<String>,
     modes: Option<String>,
@@ -163,6 +173,10 @@ impl ResponseParams {
         }
     }
 
+    pub fn command(mut self, command: impl Into<String>) -> Self {
+        self.command = Some(command.into());
+        self
+    }
     pub fn channel(mut self, channel: impl Into<String>) -> Self {
         self.channel = Some(channel.into());
         self
@@ -171,6 +185,14 @@ impl ResponseParams {
         self.nick = Some(nick.into());
         self
     }
+    pub fn user(mut self, user: impl Into<String>) -> Self {
+        self.user = Some(user.into());
+        self
+    }
+    pub fn account(mut self, account: impl Into<String>) -> Self {
+        self.account = Some(account.into());
+        self
+    }
     pub fn host(mut self, host: impl Into<String>) -> Self {
         self.host = Some(host.into());
         self
@@ -206,41 +228,50 @@ impl ResponseCode {
         match self {
             // Welcome/Connection Registration (001-015)
             ResponseCode::RPL_WELCOME => format!(
-                ":server {} {} :Welcome to the {} Network, {}\r\n",
+                ":server {:03} {} :Welcome to the {} Network, {}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
+                params.message.unwrap_or_default(),
                 params.client
             ), //"<client> :Welcome to the <networkname> Network, <nick>[!<user>@<host>]"
             ResponseCode::RPL_YOURHOST => format!(
-                ":server {} {} :Your host is {}, running version {}\r\n",
+                ":server {:03} {} :Your host is {}, running version {}-{}\r\n",
                 u16::from(*self),
                 params.client,
-                "OxideIRC\r\n",
-                "0.1"
+                params.server.unwrap_or_default(),
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
             ), //"<client> :Your host is <servername>, running version <version>"
             ResponseCode::RPL_CREATED => format!(
-                ":server {} {} :This server was created {}\r\n",
+                ":server {:03} {} :This server was created {}\r\n",
                 u16::from(*self),
                 params.client,
                 params.date.unwrap_or_default()
             ), //"<client> :This server was created <datetime>"
             ResponseCode::RPL_MYINFO => format!(
-                ":server {} {} {} {} {} {} {}\r\n",
+                ":server {:03} {} {} {}-{} {} {}{} {}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub
+                params.server.unwrap_or_default(),
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION"),
+                USER_MODES,
+                CHANNEL_MODES,
+                CHANNEL_PARAM_MODES,
+                CHANNEL_PARAM_MODES
             ), //"<client> <servername> <version> <available user modes> <available channel modes> [<channel modes with a parameter>]"
             ResponseCode::RPL_ISUPPORT => format!(
-                ":server {} {} {}, :are supported by this server\r\n",
+                ":server {:03} {} {} :are supported by this server\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> <1-13 tokens> :are supported by this server"
+            ResponseCode::RPL_SNOMASKIS => format!(
+                ":server {:03} {} {} :Server notice mask\r\n",
+                u16::from(*self),
+                params.client,
+                params.modes.unwrap_or_default()
+            ), //"<client> <snomask> :Server notice mask"
             ResponseCode::RPL_BOUNCE => format!(
                 ":server {} {} {} {} :{}\r\n",
                 u16::from(*self),
@@ -262,17 +293,43 @@ impl ResponseCode {
                 ":server {} {} {} :End of STATS report\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.modes.unwrap_or_default()
             ), //"<client> <command> :End of STATS report"
+            ResponseCode::RPL_STATSKLINE => format!(
+                ":server {} {} {} {} :{}\r\n",
+                u16::from(*self),
+                params.client,
+                params.modes.unwrap_or_default(),
+                params.host.unwrap_or_default(),
+                params.message.unwrap_or_default()
+            ), //"<client> K|G <user@host> :<reason>"
+            ResponseCode::RPL_STATSDLINE => format!(
+                ":server {} {} D {} :{}\r\n",
+                u16::from(*self),
+                params.client,
+                params.host.unwrap_or_default(),
+                params.message.unwrap_or_default()
+            ), //"<client> D <address> :<reason>"
+            ResponseCode::RPL_STATSPLINE => format!(
+                ":server {} {} P {} {} :{}\r\n",
+                u16::from(*self),
+                params.client,
+                params.server.unwrap_or_default(),
+                params.count.unwrap_or_default(),
+                params.message.unwrap_or_default()
+            ), //"<client> P <listener> <connections> :<flags>"
             ResponseCode::RPL_STATSUPTIME => format!(
                 ":server {} {} :Server Up {}\r\n",
                 u16::from(*self),
                 params.client,
                 params.date.unwrap_or_default()
             ), //"<client> :Server Up <days> days <hours>:<minutes>:<seconds>"
-            ResponseCode::RPL_UMODEIS => {
-                format!(":server {} {} {}\r\n", u16::from(*self), params.client, "")
-            } //"<client> <usermodes>"
+            ResponseCode::RPL_UMODEIS => format!(
+                ":server {} {} {}\r\n",
+                u16::from(*self),
+                params.client,
+                params.modes.unwrap_or_default()
+            ), //"<client> <usermodes>"
             ResponseCode::RPL_LUSERCLIENT => format!(
                 ":server {} {} :There are {} users and {} invisible on {} servers\r\n",
                 u16::from(*self),
@@ -330,24 +387,24 @@ impl ResponseCode {
                 ":server {} {} {} {} {} * :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.user.unwrap_or_default(),
+                params.host.unwrap_or_default(),
+                params.message.unwrap_or_default()
             ), //"<client> <nick> <username> <host> * :<realname>"
             ResponseCode::RPL_WHOISSERVER => format!(
                 ":server {} {} {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.server.unwrap_or_default(),
+                params.message.unwrap_or_default()
             ), //"<client> <nick> <server> :<server info>"
             ResponseCode::RPL_WHOISOPERATOR => format!(
                 ":server {} {} {} :is an IRC operator\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.nick.unwrap_or_default()
             ), //"<client> <nick> :is an IRC operator"
             ResponseCode::RPL_WHOWASUSER => format!(
                 ":server {} {} {} {} {} * :{}\r\n",
@@ -370,34 +427,34 @@ impl ResponseCode {
                 ":server {} {} {} :End of /WHOIS list\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.nick.unwrap_or_default()
             ), //"<client> <nick> :End of /WHOIS list"
             ResponseCode::RPL_WHOISCHANNELS => format!(
                 ":server {} {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.message.unwrap_or_default()
             ), //"<client> <nick> :<channels>"
             ResponseCode::RPL_WHOISREGNICK => format!(
                 ":server {} {} {} :has identified for this nick\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.nick.unwrap_or_default()
             ), //"<client> <nick> :has identified for this nick"
             ResponseCode::RPL_WHOISACCOUNT => format!(
                 ":server {} {} {} {} :is logged in as\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.account.unwrap_or_default()
             ), //"<client> <nick> <account> :is logged in as"
             ResponseCode::RPL_WHOISACTUALLY => format!(
-                ":server {} {} {} :is actually using host {}\r\n",
+                ":server {} {} {} {} :Is actually using host\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.host.unwrap_or_default()
             ), //"<client> <nick> <host|ip> :Is actually using host"
             ResponseCode::RPL_WHOISHOST => format!(
                 ":server {} {} {} :is connecting from *@{} {}\r\n",
@@ -418,14 +475,14 @@ impl ResponseCode {
                 ":server {} {} {} :has client certificate fingerprint {}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.message.unwrap_or_default()
             ), //"<client> <nick> :has client certificate fingerprint <fingerprint>"
             ResponseCode::RPL_WHOISSECURE => format!(
                 ":server {} {} {} :is using a secure connection\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.nick.unwrap_or_default()
             ), //"<client> <nick> :is using a secure connection"
             ResponseCode::RPL_ENDOFWHOWAS => format!(
                 ":server {} {} {} :End of WHOWAS\r\n",
@@ -437,14 +494,14 @@ impl ResponseCode {
                 ":server {} {} {} :End of WHO list\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.nick.unwrap_or_default()
             ), //"<client> <name> :End of WHO list"
             ResponseCode::RPL_WHOISSPECIAL => format!(
                 ":server {} {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.message.unwrap_or_default()
             ), //"<client> <nick> :blah blah blah"
 
             // WHO Responses
@@ -453,13 +510,13 @@ impl ResponseCode {
                 u16::from(*self),
                 params.client,
                 params.channel.unwrap_or_default(),
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub,
-                params.stub
+                params.user.unwrap_or_default(),
+                params.host.unwrap_or_default(),
+                params.server.unwrap_or_default(),
+                params.nick.unwrap_or_default(),
+                params.modes.unwrap_or_default(),
+                0,
+                params.message.unwrap_or_default()
             ), //"<client> <channel> <user> <host> <server> <nick> <flags> :<hopcount> <realname>"
 
             // Channel Operations (320-399)
@@ -482,12 +539,11 @@ impl ResponseCode {
                 params.client
             ), //"<client> :End of /LIST"
             ResponseCode::RPL_CHANNELMODEIS => format!(
-                ":server {} {} {} {} {}\r\n",
+                ":server {} {} {} {}\r\n",
                 u16::from(*self),
                 params.client,
                 params.channel.unwrap_or_default(),
-                params.stub,
-                params.stub
+                params.modes.unwrap_or_default()
             ), //"<client> <channel> <modestring> <mode arguments>..."
             ResponseCode::RPL_NOTOPIC => format!(
                 ":server {} {} {} :No topic is set\r\n",
@@ -500,15 +556,15 @@ impl ResponseCode {
                 u16::from(*self),
                 params.client,
                 params.channel.unwrap_or_default(),
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> <channel> :<topic>"
             ResponseCode::RPL_TOPICWHOTIME => format!(
                 ":server {} {} {} {} {}\r\n",
                 u16::from(*self),
                 params.client,
                 params.channel.unwrap_or_default(),
-                params.stub,
-                params.stub
+                params.nick.unwrap_or_default(),
+                params.date.unwrap_or_default()
             ), //"<client> <channel> <who> <setat>"
             ResponseCode::RPL_NAMREPLY => format!(
                 ":server {} {} {} {} :{}\r\n",
@@ -582,9 +638,9 @@ impl ResponseCode {
                 u16::from(*self),
                 params.client,
                 params.channel.unwrap_or_default(),
-                params.stub,
-                params.stub,
-                params.stub
+                params.host.unwrap_or_default(),
+                params.nick.unwrap_or_default(),
+                params.date.unwrap_or_default()
             ), //"<client> <channel> <mask> <who> <set-ts>"
             ResponseCode::RPL_ENDOFBANLIST => format!(
                 ":server {} {} {} :End of channel ban list\r\n",
@@ -595,10 +651,10 @@ impl ResponseCode {
 
             // Server Information (370-399)
             ResponseCode::RPL_MOTD => format!(
-                ":server {} {} :{}\r\n",
+                ":server {} {} :- {}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> :<line of motd>"
             ResponseCode::RPL_MOTDSTART => format!(
                 ":server {} {} :- {} Message of the day - \r\n",
@@ -680,27 +736,28 @@ impl ResponseCode {
 
             // Administrative Information
             ResponseCode::RPL_ADMINME => format!(
-                ":server {} {} :Administrative info\r\n",
+                ":server {} {} {} :Administrative info\r\n",
                 u16::from(*self),
-                params.client
-            ), //"<client> :Administrative info"
+                params.client,
+                params.server.unwrap_or_default()
+            ), //"<client> [<server>] :Administrative info"
             ResponseCode::RPL_ADMINLOC1 => format!(
                 ":server {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> :<info>"
             ResponseCode::RPL_ADMINLOC2 => format!(
                 ":server {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> :<info>"
             ResponseCode::RPL_ADMINEMAIL => format!(
                 ":server {} {} :{}\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> :<info>"
 
             // Operator Commands
@@ -713,7 +770,7 @@ impl ResponseCode {
                 ":server {} {} {} :Rehashing\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> <config file> :Rehashing"
 
             // Error Responses (400-599)
@@ -838,7 +895,7 @@ impl ResponseCode {
                 ":server {} {} {} :is unknown mode char to me\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.modes.unwrap_or_default()
             ), //"<client> <char> :is unknown mode char to me"
             ResponseCode::ERR_INVITEONLYCHAN => format!(
                 ":server {} {} {} :Cannot join channel (+i)\r\n",
@@ -873,7 +930,7 @@ impl ResponseCode {
                 ":server {} {} {} :Not enough parameters\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.command.unwrap_or_default()
             ), //"<client> <command> :Not enough parameters"
             ResponseCode::ERR_ALREADYREGISTERED => format!(
                 ":server {} {} :You may not reregister\r\n",
@@ -950,7 +1007,7 @@ impl ResponseCode {
                 ":server {} {} {} :Insufficient oper privileges.\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> <priv> :Insufficient oper privileges."
             ResponseCode::RPL_STARTTLS => format!(
                 ":server {} {} :STARTTLS successful, proceed with TLS handshake\r\n",
@@ -961,7 +1018,7 @@ impl ResponseCode {
                 ":server {} {} :STARTTLS failed ({})\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> :STARTTLS failed (<reason>)"
             ResponseCode::RPL_TRYAGAIN => format!(
                 ":server {} {} {} :Please wait a while and try again.\r\n",
@@ -971,30 +1028,33 @@ impl ResponseCode {
             ), //"<client> <command> :Please wait a while and try again."
 
             // SASL Authentication (900-999)
-            ResponseCode::RPL_LOGGEDIN => format!(
-                ":server {} {} {} {} :You are now logged in as {}\r\n",
-                u16::from(*self),
-                params.client,
-                params.nick.unwrap_or_default(),
-                params.stub,
-                params.stub
-            ), //"<client> <nick> <user> <account> :You are now logged in as <account>"
+            ResponseCode::RPL_LOGGEDIN => {
+                let account = params.account.unwrap_or_default();
+                format!(
+                    ":server {} {} {} {} :You are now logged in as {}\r\n",
+                    u16::from(*self),
+                    params.client,
+                    params.nick.unwrap_or_default(),
+                    account,
+                    account
+                )
+            } //"<client> <nick>!<user>@<host> <account> :You are now logged in as <account>"
             ResponseCode::RPL_LOGGEDOUT => format!(
-                ":server {}{} {} :You are now logged out\r\n",
+                ":server {} {} {} :You are now logged out\r\n",
                 u16::from(*self),
                 params.client,
                 params.nick.unwrap_or_default()
-            ), //"<client> <nick> :You are now logged out"
+            ), //"<client> <nick>!<user>@<host> :You are now logged out"
             ResponseCode::RPL_SASLSUCCESS => format!(
                 ":server {} {} :SASL authentication successful\r\n",
                 u16::from(*self),
                 params.client
             ), //"<client> :SASL authentication successful"
             ResponseCode::RPL_SASLMECHS => format!(
-                ":server {} {} :{}\r\n",
+                ":server {} {} {} :are available SASL mechanisms\r\n",
                 u16::from(*self),
                 params.client,
-                params.stub
+                params.message.unwrap_or_default()
             ), //"<client> <mechanisms> :are available SASL mechanisms"
             ResponseCode::ERR_NICKLOCKED => format!(
                 ":server {} {} :You must use a nick assigned to you\r\n",
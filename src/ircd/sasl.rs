@@ -0,0 +1,314 @@
+use std::sync::Arc;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use tokio::sync::RwLock;
+
+use super::accounts::{self, log_in};
+use super::client::Client;
+use super::ircd::SharedServerState;
+use super::response::{ResponseCode, ResponseParams};
+
+/// AUTHENTICATE payloads are split into chunks of this size; a full chunk
+/// means more data follows.
+const CHUNK_SIZE: usize = 400;
+const MAX_PAYLOAD: usize = 8192;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Mechanism {
+    Plain,
+    External,
+}
+
+impl Mechanism {
+    pub const SUPPORTED: [Mechanism; 2] = [Mechanism::Plain, Mechanism::External];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mechanism::Plain => "PLAIN",
+            Mechanism::External => "EXTERNAL",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::SUPPORTED
+            .into_iter()
+            .find(|mechanism| mechanism.name().eq_ignore_ascii_case(name))
+    }
+}
+
+#[derive(Debug)]
+pub struct SaslSession {
+    pub mechanism: Mechanism,
+    pub buffer: String,
+}
+
+/// Where an exchange stands after an AUTHENTICATE chunk.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Progress {
+    More,
+    Complete,
+    TooLong,
+}
+
+impl SaslSession {
+    /// Appends a chunk of the payload; `+` stands for an empty chunk.
+    pub fn push(&mut self, chunk: &str) -> Progress {
+        if chunk != "+" {
+            self.buffer.push_str(chunk);
+        }
+        if self.buffer.len() > MAX_PAYLOAD {
+            Progress::TooLong
+        } else if chunk.len() == CHUNK_SIZE {
+            Progress::More
+        } else {
+            Progress::Complete
+        }
+    }
+}
+
+#[tracing::instrument(name = "Handling SASL exchange", skip_all)]
+pub async fn authenticate(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    param: &str,
+) {
+    let (nickname, certfp, exchange) = {
+        let mut active_session = session.write().await;
+        let nickname = active_session.nick.as_ref().unwrap().clone();
+        let reply = |code: ResponseCode| code.message(ResponseParams::new(nickname.clone()));
+
+        if active_session.account.is_some() {
+            let _ = active_session
+                .sender
+                .send(reply(ResponseCode::ERR_SASLALREADY));
+            return;
+        }
+
+        if param == "*" {
+            let code = match active_session.sasl.take() {
+                Some(_) => ResponseCode::ERR_SASLABORTED,
+                None => ResponseCode::ERR_SASLFAIL,
+            };
+            let _ = active_session.sender.send(reply(code));
+            return;
+        }
+
+        let Some(sasl) = active_session.sasl.as_mut() else {
+            match Mechanism::from_name(param) {
+                Some(mechanism) => {
+                    active_session.sasl = Some(SaslSession {
+                        mechanism,
+                        buffer: String::new(),
+                    });
+                    let _ = active_session.sender.send("AUTHENTICATE +\r\n".to_string());
+                }
+                None => {
+                    let mechanisms = Mechanism::SUPPORTED
+                        .iter()
+                        .map(Mechanism::name)
+                        .collect::<Vec<_>>()
+                        .join(",");
+                    let params = ResponseParams::new(nickname.clone()).message(mechanisms);
+                    let _ = active_session
+                        .sender
+                        .send(ResponseCode::RPL_SASLMECHS.message(params));
+                    let _ = active_session
+                        .sender
+                        .send(reply(ResponseCode::ERR_SASLFAIL));
+                }
+            }
+            return;
+        };
+
+        match sasl.push(param) {
+            Progress::More => return,
+            Progress::TooLong => {
+                active_session.sasl = None;
+                let _ = active_session
+                    .sender
+                    .send(reply(ResponseCode::ERR_SASLTOOLONG));
+                return;
+            }
+            Progress::Complete => {}
+        }
+
+        (
+            nickname,
+            active_session.certfp.clone(),
+            active_session.sasl.take().unwrap(),
+        )
+    };
+
+    let result = match exchange.mechanism {
+        Mechanism::Plain => match decode_plain(&exchange.buffer) {
+            Some((authcid, password)) => accounts::authenticate(server_state, &authcid, &password)
+                .await
+                .ok(),
+            None => None,
+        },
+        Mechanism::External => match (certfp, decode_external(&exchange.buffer)) {
+            (Some(certfp), Some(authzid)) => server_state
+                .accounts
+                .read()
+                .await
+                .certfp_owner(&certfp)
+                .filter(|account| authzid.is_empty() || account.eq_ignore_ascii_case(&authzid)),
+            _ => None,
+        },
+    };
+
+    match result {
+        Some(account) => {
+            log_in(session, server_state, account).await;
+            let _ = session
+                .read()
+                .await
+                .sender
+                .send(ResponseCode::RPL_SASLSUCCESS.message(ResponseParams::new(nickname)));
+        }
+        None => {
+            tracing::info!("SASL {} failed for {}", exchange.mechanism.name(), nickname);
+            let _ = session
+                .read()
+                .await
+                .sender
+                .send(ResponseCode::ERR_SASLFAIL.message(ResponseParams::new(nickname)));
+        }
+    }
+}
+
+/// Decodes a PLAIN payload (`authzid NUL authcid NUL password`), rejecting
+/// attempts to authorize as a different identity.
+fn decode_plain(payload: &str) -> Option<(String, String)> {
+    let decoded = STANDARD.decode(payload).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let mut fields = decoded.split('\0');
+    let (authzid, authcid, password) = (fields.next()?, fields.next()?, fields.next()?);
+    if !authzid.is_empty() && authzid != authcid {
+        return None;
+    }
+    Some((authcid.to_string(), password.to_string()))
+}
+
+/// Decodes an EXTERNAL payload, which is just the optional authzid.
+fn decode_external(payload: &str) -> Option<String> {
+    if payload.is_empty() {
+        return Some(String::new());
+    }
+    String::from_utf8(STANDARD.decode(payload).ok()?).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session() -> SaslSession {
+        SaslSession {
+            mechanism: Mechanism::Plain,
+            buffer: String::new(),
+        }
+    }
+
+    #[test]
+    fn a_short_chunk_completes_the_payload() {
+        let mut sasl = session();
+        assert_eq!(sasl.push("abc"), Progress::Complete);
+        assert_eq!(sasl.buffer, "abc");
+    }
+
+    #[test]
+    fn full_chunks_wait_for_more() {
+        let mut sasl = session();
+        let full = "A".repeat(CHUNK_SIZE);
+        assert_eq!(sasl.push(&full), Progress::More);
+        assert_eq!(sasl.push(&full), Progress::More);
+        assert_eq!(sasl.push("xyz"), Progress::Complete);
+        assert_eq!(sasl.buffer.len(), 2 * CHUNK_SIZE + 3);
+    }
+
+    #[test]
+    fn plus_ends_a_payload_that_filled_its_last_chunk() {
+        let mut sasl = session();
+        let full = "A".repeat(CHUNK_SIZE);
+        assert_eq!(sasl.push(&full), Progress::More);
+        assert_eq!(sasl.push("+"), Progress::Complete);
+        assert_eq!(sasl.buffer, full);
+    }
+
+    #[test]
+    fn plus_alone_is_an_empty_payload() {
+        let mut sasl = session();
+        assert_eq!(sasl.push("+"), Progress::Complete);
+        assert!(sasl.buffer.is_empty());
+    }
+
+    #[test]
+    fn payloads_over_the_limit_are_rejected() {
+        let mut sasl = session();
+        let full = "A".repeat(CHUNK_SIZE);
+        for _ in 0..MAX_PAYLOAD / CHUNK_SIZE {
+            assert_eq!(sasl.push(&full), Progress::More);
+        }
+        assert_eq!(sasl.push(&full), Progress::TooLong);
+    }
+
+    #[test]
+    fn a_payload_of_exactly_the_limit_is_accepted() {
+        let mut sasl = session();
+        sasl.buffer = "A".repeat(MAX_PAYLOAD - 1);
+        assert_eq!(sasl.push("A"), Progress::Complete);
+    }
+
+    #[test]
+    fn mechanism_names_are_case_insensitive() {
+        assert!(matches!(
+            Mechanism::from_name("plain"),
+            Some(Mechanism::Plain)
+        ));
+        assert!(matches!(
+            Mechanism::from_name("External"),
+            Some(Mechanism::External)
+        ));
+        assert!(Mechanism::from_name("SCRAM-SHA-256").is_none());
+    }
+
+    #[test]
+    fn decode_plain_splits_the_fields() {
+        let payload = STANDARD.encode("\0alice\0secret");
+        assert_eq!(
+            decode_plain(&payload),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+        let payload = STANDARD.encode("alice\0alice\0secret");
+        assert_eq!(
+            decode_plain(&payload),
+            Some(("alice".to_string(), "secret".to_string()))
+        );
+    }
+
+    #[test]
+    fn decode_plain_rejects_a_different_authzid() {
+        let payload = STANDARD.encode("bob\0alice\0secret");
+        assert_eq!(decode_plain(&payload), None);
+    }
+
+    #[test]
+    fn decode_plain_rejects_malformed_payloads() {
+        assert_eq!(decode_plain("not base64!"), None);
+        assert_eq!(decode_plain(&STANDARD.encode("alice\0secret")), None);
+        assert_eq!(
+            decode_plain(&STANDARD.encode([0xff, 0, 0x61, 0, 0x62])),
+            None
+        );
+    }
+
+    #[test]
+    fn decode_external_reads_the_optional_authzid() {
+        assert_eq!(decode_external(""), Some(String::new()));
+        assert_eq!(
+            decode_external(&STANDARD.encode("alice")),
+            Some("alice".to_string())
+        );
+        assert_eq!(decode_external("***"), None);
+    }
+}
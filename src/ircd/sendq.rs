@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc::{self, error::SendError};
+use tokio::sync::Notify;
+
+#[derive(Debug)]
+struct Shared {
+    /// Bytes sent but not yet taken off the queue
+    queued: AtomicUsize,
+    limit: AtomicUsize,
+    /// Why the connection is being closed, once it is
+    closed: Mutex<Option<CloseReason>>,
+    notify: Notify,
+}
+
+tokio::task_local! {
+    /// Replies collected for the command running on this task
+    static CAPTURE: Capture;
+}
+
+struct Capture {
+    /// Identifies the queue being captured
+    shared: Arc<Shared>,
+    lines: RefCell<Vec<String>>,
+}
+
+/// Why the server is closing a connection.
+#[derive(Debug, Clone)]
+pub struct CloseReason {
+    /// Sent to the client in its final ERROR
+    pub error: String,
+    /// Shown to others in the client's QUIT
+    pub quit: String,
+}
+
+impl CloseReason {
+    pub fn new(reason: impl Into<String>) -> Self {
+        let reason = reason.into();
+        Self {
+            error: reason.clone(),
+            quit: reason,
+        }
+    }
+}
+
+/// Sending half of a client's SendQ. Messages are counted in bytes until
+/// the connection writes them out; a send that takes the total over the
+/// limit is refused and closes the queue, so a stalled client cannot make
+/// the server buffer without bound.
+#[derive(Debug, Clone)]
+pub struct SendQueue {
+    sender: mpsc::UnboundedSender<String>,
+    shared: Arc<Shared>,
+}
+
+#[derive(Debug)]
+pub struct SendQueueReceiver {
+    receiver: mpsc::UnboundedReceiver<String>,
+    shared: Arc<Shared>,
+}
+
+pub fn channel(limit: usize) -> (SendQueue, SendQueueReceiver) {
+    let shared = Arc::new(Shared {
+        queued: AtomicUsize::new(0),
+        limit: AtomicUsize::new(limit),
+        closed: Mutex::new(None),
+        notify: Notify::new(),
+    });
+    let (sender, receiver) = mpsc::unbounded_channel();
+    (
+        SendQueue {
+            sender,
+            shared: shared.clone(),
+        },
+        SendQueueReceiver { receiver, shared },
+    )
+}
+
+impl SendQueue {
+    pub fn send(&self, message: String) -> Result<(), SendError<String>> {
+        if self.is_closed() {
+            return Err(SendError(message));
+        }
+        let captured = CAPTURE
+            .try_with(|capture| Arc::ptr_eq(&capture.shared, &self.shared))
+            .unwrap_or(false);
+        if captured {
+            CAPTURE.with(|capture| capture.lines.borrow_mut().push(message));
+            return Ok(());
+        }
+        let size = message.len();
+        let queued = self.shared.queued.fetch_add(size, Ordering::AcqRel) + size;
+        if queued > self.shared.limit.load(Ordering::Acquire) {
+            self.shared.queued.fetch_sub(size, Ordering::AcqRel);
+            self.close("SendQ exceeded");
+            return Err(SendError(message));
+        }
+        self.sender.send(message).inspect_err(|_| {
+            self.shared.queued.fetch_sub(size, Ordering::AcqRel);
+        })
+    }
+
+    pub fn set_limit(&self, limit: usize) {
+        self.shared.limit.store(limit, Ordering::Release);
+    }
+
+    /// Asks the connection to close, giving the same reason to the client
+    /// and to everyone who sees it quit.
+    pub fn close(&self, reason: &str) {
+        self.close_link(CloseReason::new(reason));
+    }
+
+    /// Asks the connection to close. Only the first reason given is kept.
+    pub fn close_link(&self, reason: CloseReason) {
+        let mut closed = self.shared.closed.lock().unwrap();
+        if closed.is_none() {
+            *closed = Some(reason);
+            self.shared.notify.notify_one();
+        }
+    }
+
+    /// Waits until the queue is closed and returns the reason. Cancel safe.
+    pub async fn closed(&self) -> CloseReason {
+        loop {
+            if let Some(reason) = self.shared.closed.lock().unwrap().clone() {
+                return reason;
+            }
+            self.shared.notify.notified().await;
+        }
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.shared.closed.lock().unwrap().is_some()
+    }
+
+    /// Runs `future`, collecting what it sends to this queue instead of
+    /// queueing it. Only sends made by `future` itself are collected;
+    /// other tasks writing to the client meanwhile reach it as usual.
+    pub async fn capture<F: Future>(&self, future: F) -> (F::Output, Vec<String>) {
+        let capture = Capture {
+            shared: self.shared.clone(),
+            lines: RefCell::new(vec![]),
+        };
+        CAPTURE
+            .scope(capture, async {
+                let output = future.await;
+                let lines = CAPTURE.with(|capture| capture.lines.take());
+                (output, lines)
+            })
+            .await
+    }
+
+    /// A fresh queue sharing this one's byte count and limit, used to
+    /// divert a client's messages without losing track of its SendQ.
+    pub fn redirect(&self) -> (SendQueue, SendQueueReceiver) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        (
+            SendQueue {
+                sender,
+                shared: self.shared.clone(),
+            },
+            SendQueueReceiver {
+                receiver,
+                shared: self.shared.clone(),
+            },
+        )
+    }
+}
+
+impl SendQueueReceiver {
+    pub async fn recv(&mut self) -> Option<String> {
+        let message = self.receiver.recv().await?;
+        self.shared
+            .queued
+            .fetch_sub(message.len(), Ordering::AcqRel);
+        Some(message)
+    }
+
+    pub fn try_recv(&mut self) -> Option<String> {
+        let message = self.receiver.try_recv().ok()?;
+        self.shared
+            .queued
+            .fetch_sub(message.len(), Ordering::AcqRel);
+        Some(message)
+    }
+}
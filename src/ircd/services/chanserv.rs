@@ -0,0 +1,257 @@
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use super::{notice, CHANSERV};
+use crate::ircd::channel::CHANNEL_MODES;
+use crate::ircd::channel_registry::ACCESS_FLAGS;
+use crate::ircd::client::Client;
+use crate::ircd::ircd::SharedServerState;
+
+const HELP: [&str; 8] = [
+    "REGISTER <#channel> - register a channel you are an operator in",
+    "DROP <#channel> - drop a channel registration (founder only)",
+    "ACCESS <#channel> LIST - show the access list",
+    "ACCESS <#channel> ADD <account> <flags> - grant access flags (o, v, t, s)",
+    "ACCESS <#channel> DEL <account> - remove an access entry",
+    "SET <#channel> MLOCK <modes> - lock channel modes, e.g. +nt-s",
+    "INFO <#channel> - show registration details",
+    "HELP - show this list",
+];
+
+#[tracing::instrument(
+    name = "Handling ChanServ command",
+    skip(session, server_state, message)
+)]
+pub async fn handle(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    message: &str,
+) {
+    let (nickname, account) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.as_ref().unwrap().clone(),
+            active_session.account.clone(),
+        )
+    };
+    let reply = |text: &str| {
+        let session = session.clone();
+        let line = notice(CHANSERV, &nickname, text);
+        async move {
+            let _ = session.read().await.sender.send(line);
+        }
+    };
+
+    let parts = message.split_whitespace().collect::<Vec<_>>();
+    let sub_cmd = parts
+        .first()
+        .map(|s| s.to_ascii_uppercase())
+        .unwrap_or_default();
+    let Some(channel_name) = parts.get(1).filter(|name| name.starts_with('#')) else {
+        for line in HELP {
+            reply(line).await;
+        }
+        return;
+    };
+    let Some(account) = account else {
+        return reply("You must be logged in to use ChanServ").await;
+    };
+    let flags = server_state
+        .channel_registry
+        .read()
+        .await
+        .get(channel_name)
+        .map(|registration| registration.flags_for(&account));
+
+    match (sub_cmd.as_str(), flags) {
+        ("REGISTER", Some(_)) => {
+            reply(&format!("{} is already registered", channel_name)).await;
+        }
+
+        ("REGISTER", None) => {
+            let channel_obj = server_state
+                .channels
+                .read()
+                .await
+                .get(*channel_name)
+                .cloned();
+            let Some(channel_obj) = channel_obj else {
+                return reply(&format!("{} does not exist", channel_name)).await;
+            };
+            let channel = channel_obj.read().await;
+            if !channel.is_operator(&nickname) {
+                return reply(&format!(
+                    "You must be a channel operator in {}",
+                    channel_name
+                ))
+                .await;
+            }
+            server_state
+                .channel_registry
+                .write()
+                .await
+                .register(&channel, &account);
+            tracing::info!("{} registered {}", account, channel_name);
+            reply(&format!(
+                "{} is now registered to {}",
+                channel_name, account
+            ))
+            .await;
+        }
+
+        (_, None) => {
+            reply(&format!("{} is not registered", channel_name)).await;
+        }
+
+        ("DROP", Some(flags)) => {
+            let is_founder = server_state
+                .channel_registry
+                .read()
+                .await
+                .get(channel_name)
+                .is_some_and(|registration| registration.founder.eq_ignore_ascii_case(&account));
+            if !is_founder || flags.is_empty() {
+                return reply("Only the founder may drop a channel").await;
+            }
+            server_state
+                .channel_registry
+                .write()
+                .await
+                .drop_channel(channel_name);
+            let channel_obj = server_state
+                .channels
+                .read()
+                .await
+                .get(*channel_name)
+                .cloned();
+            if let Some(channel_obj) = channel_obj {
+                channel_obj.write().await.mlock.clear();
+            }
+            reply(&format!("{} has been dropped", channel_name)).await;
+        }
+
+        ("ACCESS", Some(flags)) => {
+            let action = parts.get(2).map(|s| s.to_ascii_uppercase());
+            match (action.as_deref(), parts.get(3), parts.get(4)) {
+                (Some("LIST") | None, _, _) => {
+                    let entries = server_state
+                        .channel_registry
+                        .read()
+                        .await
+                        .get(channel_name)
+                        .map(|registration| {
+                            let mut entries = vec![format!("{} founder", registration.founder)];
+                            entries.extend(
+                                registration
+                                    .access
+                                    .iter()
+                                    .map(|entry| format!("{} +{}", entry.account, entry.flags)),
+                            );
+                            entries
+                        })
+                        .unwrap_or_default();
+                    reply(&format!("Access list for {}:", channel_name)).await;
+                    for entry in entries {
+                        reply(&entry).await;
+                    }
+                }
+                (Some("ADD" | "DEL"), _, _) if !flags.contains('s') => {
+                    reply("You do not have access to change the access list").await;
+                }
+                (Some("ADD"), Some(target), Some(new_flags)) => {
+                    let new_flags = new_flags.trim_start_matches('+');
+                    if new_flags.is_empty() || !new_flags.chars().all(|c| ACCESS_FLAGS.contains(c))
+                    {
+                        return reply(&format!("Flags must be drawn from {}", ACCESS_FLAGS)).await;
+                    }
+                    if server_state.accounts.read().await.get(target).is_none() {
+                        return reply(&format!("{} is not a registered account", target)).await;
+                    }
+                    server_state.channel_registry.write().await.set_access(
+                        channel_name,
+                        target,
+                        new_flags,
+                    );
+                    reply(&format!(
+                        "{} now has +{} on {}",
+                        target, new_flags, channel_name
+                    ))
+                    .await;
+                }
+                (Some("DEL"), Some(target), _) => {
+                    server_state.channel_registry.write().await.set_access(
+                        channel_name,
+                        target,
+                        "",
+                    );
+                    reply(&format!("{} removed from {}", target, channel_name)).await;
+                }
+                _ => reply("Syntax: ACCESS <#channel> LIST|ADD|DEL [account] [flags]").await,
+            }
+        }
+
+        ("SET", Some(flags)) => {
+            if !flags.contains('s') {
+                return reply("You do not have access to change settings").await;
+            }
+            let option = parts.get(2).map(|s| s.to_ascii_uppercase());
+            match (option.as_deref(), parts.get(3)) {
+                (Some("MLOCK"), modes) => {
+                    let modes = modes.copied().unwrap_or_default();
+                    if !modes
+                        .chars()
+                        .all(|c| c == '+' || c == '-' || CHANNEL_MODES.contains(c))
+                    {
+                        return reply(&format!("Only {} may be locked", CHANNEL_MODES)).await;
+                    }
+                    server_state
+                        .channel_registry
+                        .write()
+                        .await
+                        .set_mlock(channel_name, modes);
+                    let channel_obj = server_state
+                        .channels
+                        .read()
+                        .await
+                        .get(*channel_name)
+                        .cloned();
+                    if let Some(channel_obj) = channel_obj {
+                        let mut channel = channel_obj.write().await;
+                        channel.mlock = modes.to_string();
+                        channel.apply_mlock();
+                        server_state.channel_registry.write().await.sync(&channel);
+                    }
+                    reply(&format!("Mode lock for {} set to {}", channel_name, modes)).await;
+                }
+                _ => reply("Syntax: SET <#channel> MLOCK <modes>").await,
+            }
+        }
+
+        ("INFO", Some(_)) => {
+            let info = server_state
+                .channel_registry
+                .read()
+                .await
+                .get(channel_name)
+                .map(|registration| {
+                    vec![
+                        format!("Information on {}:", registration.name),
+                        format!("Founder: {}", registration.founder),
+                        format!("Registered: {}", registration.registered_at),
+                        format!("Mode lock: {}", registration.mlock),
+                    ]
+                })
+                .unwrap_or_default();
+            for line in info {
+                reply(&line).await;
+            }
+        }
+
+        _ => {
+            for line in HELP {
+                reply(line).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::Arc;
+
+use tokio::sync::RwLock;
+
+use super::client::Client;
+use super::ircd::SharedServerState;
+
+pub mod chanserv;
+pub mod nickserv;
+
+pub const NICKSERV: &str = "NickServ";
+pub const CHANSERV: &str = "ChanServ";
+
+pub fn is_service(nick: &str) -> bool {
+    nick.eq_ignore_ascii_case(NICKSERV) || nick.eq_ignore_ascii_case(CHANSERV)
+}
+
+pub fn notice(service: &str, nick: &str, text: &str) -> String {
+    format!(
+        ":{}!{}@services NOTICE {} :{}\r\n",
+        service, service, nick, text
+    )
+}
+
+/// Hands a PRIVMSG addressed to a services pseudo-client over to it.
+/// Returns false when `target` is not a service.
+pub async fn dispatch(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    target: &str,
+    message: &str,
+) -> bool {
+    if target.eq_ignore_ascii_case(NICKSERV) {
+        nickserv::handle(session, server_state, message).await;
+        true
+    } else if target.eq_ignore_ascii_case(CHANSERV) {
+        chanserv::handle(session, server_state, message).await;
+        true
+    } else {
+        false
+    }
+}
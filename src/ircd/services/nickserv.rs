@@ -0,0 +1,269 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use rand::Rng;
+use tokio::sync::RwLock;
+
+use super::{notice, NICKSERV};
+use crate::ircd::accounts::{self, log_in, log_out};
+use crate::ircd::client::Client;
+use crate::ircd::command::Command;
+use crate::ircd::ircd::SharedServerState;
+
+const HELP: [&str; 8] = [
+    "REGISTER <password> [email] - register your current nick as an account",
+    "IDENTIFY [account] <password> - log into an account",
+    "GROUP - add your current nick to the account you are logged into",
+    "DROP <password> - delete the account you are logged into and its channels",
+    "SET PASSWORD <new password> - change your account password",
+    "CERT ADD|DEL|LIST [fingerprint] - manage certificates accepted by SASL EXTERNAL",
+    "LOGOUT - log out of your account",
+    "HELP - show this list",
+];
+
+#[tracing::instrument(
+    name = "Handling NickServ command",
+    skip(session, server_state, message)
+)]
+pub async fn handle(
+    session: &Arc<RwLock<Client>>,
+    server_state: &SharedServerState,
+    message: &str,
+) {
+    let (nickname, account, certfp) = {
+        let active_session = session.read().await;
+        (
+            active_session.nick.as_ref().unwrap().clone(),
+            active_session.account.clone(),
+            active_session.certfp.clone(),
+        )
+    };
+    let reply = |text: &str| {
+        let session = session.clone();
+        let line = notice(NICKSERV, &nickname, text);
+        async move {
+            let _ = session.read().await.sender.send(line);
+        }
+    };
+
+    let parts = message.split_whitespace().collect::<Vec<_>>();
+    let sub_cmd = parts
+        .first()
+        .map(|s| s.to_ascii_uppercase())
+        .unwrap_or_default();
+    match (sub_cmd.as_str(), account) {
+        ("REGISTER", Some(_)) | ("IDENTIFY", Some(_)) => {
+            reply("You are already logged in").await;
+        }
+
+        ("REGISTER", None) => {
+            let Some(password) = parts.get(1) else {
+                return reply("Syntax: REGISTER <password> [email]").await;
+            };
+            let email = parts.get(2).map(|s| s.to_string());
+            let result = accounts::register(server_state, &nickname, password, email).await;
+            match result {
+                Ok(()) => {
+                    reply(&format!("Nickname {} is now registered", nickname)).await;
+                    log_in(session, server_state, nickname.clone()).await;
+                }
+                Err(e) => reply(&e.to_string()).await,
+            }
+        }
+
+        ("IDENTIFY", None) => {
+            let (name, password) = match parts.as_slice() {
+                [_, password] => (nickname.as_str(), *password),
+                [_, name, password, ..] => (*name, *password),
+                _ => return reply("Syntax: IDENTIFY [account] <password>").await,
+            };
+            let result = accounts::authenticate(server_state, name, password).await;
+            match result {
+                Ok(account) => {
+                    reply(&format!("You are now identified for {}", account)).await;
+                    log_in(session, server_state, account).await;
+                }
+                Err(e) => reply(&e.to_string()).await,
+            }
+        }
+
+        ("GROUP" | "DROP" | "SET" | "CERT" | "LOGOUT", None) => {
+            reply("You are not logged in").await;
+        }
+
+        ("GROUP", Some(account)) => {
+            let result = server_state
+                .accounts
+                .write()
+                .await
+                .group(&account, &nickname);
+            match result {
+                Ok(()) => reply(&format!("{} is now grouped to {}", nickname, account)).await,
+                Err(e) => reply(&e.to_string()).await,
+            }
+        }
+
+        ("DROP", Some(account)) => {
+            let Some(password) = parts.get(1) else {
+                return reply("Syntax: DROP <password>").await;
+            };
+            let result = match accounts::authenticate(server_state, &account, password).await {
+                Ok(_) => server_state.accounts.write().await.drop_account(&account),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = result {
+                return reply(&e.to_string()).await;
+            }
+            reply(&format!("Account {} has been dropped", account)).await;
+
+            let founded = server_state
+                .channel_registry
+                .write()
+                .await
+                .forget_account(&account);
+            for channel_name in founded {
+                let channel_obj = server_state
+                    .channels
+                    .read()
+                    .await
+                    .get(&channel_name)
+                    .cloned();
+                if let Some(channel_obj) = channel_obj {
+                    channel_obj.write().await.mlock.clear();
+                }
+                reply(&format!("{} has been dropped", channel_name)).await;
+            }
+
+            let sessions = server_state
+                .users
+                .read()
+                .await
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+            for handle in sessions {
+                let logged_in = handle.read().await.account.as_deref() == Some(account.as_str());
+                if logged_in {
+                    log_out(&handle, server_state).await;
+                }
+            }
+        }
+
+        ("SET", Some(account)) => match parts.as_slice() {
+            [_, option, password] if option.eq_ignore_ascii_case("PASSWORD") => {
+                let result = accounts::set_password(server_state, &account, password).await;
+                match result {
+                    Ok(()) => reply("Password changed").await,
+                    Err(e) => reply(&e.to_string()).await,
+                }
+            }
+            _ => reply("Syntax: SET PASSWORD <new password>").await,
+        },
+
+        ("CERT", Some(account)) => {
+            let option = parts
+                .get(1)
+                .map(|s| s.to_ascii_uppercase())
+                .unwrap_or_default();
+            let fingerprint = parts.get(2).map(|fp| fp.to_string()).or(certfp);
+            match (option.as_str(), fingerprint) {
+                ("LIST", _) => {
+                    let certfps = server_state
+                        .accounts
+                        .read()
+                        .await
+                        .get(&account)
+                        .map(|account| account.certfps.clone())
+                        .unwrap_or_default();
+                    if certfps.is_empty() {
+                        return reply("No certificate fingerprints registered").await;
+                    }
+                    for certfp in certfps {
+                        reply(&certfp).await;
+                    }
+                }
+                ("ADD", Some(fingerprint)) => {
+                    let result = server_state
+                        .accounts
+                        .write()
+                        .await
+                        .add_certfp(&account, &fingerprint);
+                    match result {
+                        Ok(()) => reply(&format!("Added fingerprint {}", fingerprint)).await,
+                        Err(e) => reply(&e.to_string()).await,
+                    }
+                }
+                ("DEL", Some(fingerprint)) => {
+                    let result = server_state
+                        .accounts
+                        .write()
+                        .await
+                        .remove_certfp(&account, &fingerprint);
+                    match result {
+                        Ok(()) => reply(&format!("Removed fingerprint {}", fingerprint)).await,
+                        Err(e) => reply(&e.to_string()).await,
+                    }
+                }
+                ("ADD" | "DEL", None) => {
+                    reply("You are not using a client certificate; give a fingerprint").await;
+                }
+                _ => reply("Syntax: CERT ADD|DEL|LIST [fingerprint]").await,
+            }
+        }
+
+        ("LOGOUT", Some(_)) => {
+            log_out(session, server_state).await;
+            reply("You have been logged out").await;
+        }
+
+        _ => {
+            for line in HELP {
+                reply(line).await;
+            }
+        }
+    }
+}
+
+/// Warns a user holding a nick registered to someone else and, unless they
+/// identify within the grace period, renames them to a guest nick.
+pub fn enforce_nick(session: Arc<RwLock<Client>>, server_state: SharedServerState) {
+    tokio::spawn(async move {
+        let is_protected = |nickname: String| {
+            let session = session.clone();
+            let server_state = server_state.clone();
+            async move {
+                let owner = server_state.accounts.read().await.nick_owner(&nickname);
+                let active_session = session.read().await;
+                active_session.nick.as_deref() == Some(nickname.as_str())
+                    && owner.is_some_and(|owner| {
+                        active_session
+                            .account
+                            .as_ref()
+                            .is_none_or(|account| !account.eq_ignore_ascii_case(&owner))
+                    })
+            }
+        };
+
+        let nickname = session.read().await.nick.clone().unwrap();
+        if !is_protected(nickname.clone()).await {
+            return;
+        }
+
+        let grace = server_state.config().accounts.enforce_grace_secs;
+        let _ = session.read().await.sender.send(notice(
+            NICKSERV,
+            &nickname,
+            &format!(
+                "This nickname is registered. Please identify within {} seconds or it will be changed",
+                grace
+            ),
+        ));
+        tokio::time::sleep(Duration::from_secs(grace)).await;
+
+        if is_protected(nickname.clone()).await {
+            tracing::info!("Renaming {} after failing to identify", nickname);
+            let guest = format!("Guest{}", rand::thread_rng().gen_range(1..=99999));
+            let _ = Command::NICK(guest).handle(&session, &server_state).await;
+        }
+    });
+}
@@ -0,0 +1,150 @@
+use std::fmt;
+
+use tokio::sync::mpsc;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_subscriber::filter::{filter_fn, FilterFn};
+use tracing_subscriber::layer::{Context, Layer};
+
+use super::command::server_notice;
+use super::ircd::SharedServerState;
+
+/// Events waiting to reach opers; newer ones are dropped once this many
+/// are queued.
+const EVENT_QUEUE: usize = 1024;
+
+/// A kind of server event opers can subscribe to with user mode `+s`.
+///
+/// Log events carry one as a `snomask` field, e.g.
+/// `tracing::info!(snomask = %Snomask::Kill, "...")`, and are then also
+/// sent to the opers subscribed to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Snomask {
+    /// Clients finishing registration
+    Connect,
+    /// Registered clients disconnecting
+    Quit,
+    Nick,
+    Kill,
+    /// Connections dropped or refused by K-, D- and G-lines
+    Ban,
+    /// Clients disconnected for flooding
+    Flood,
+}
+
+impl Snomask {
+    pub const ALL: [Snomask; 6] = [
+        Snomask::Connect,
+        Snomask::Quit,
+        Snomask::Nick,
+        Snomask::Kill,
+        Snomask::Ban,
+        Snomask::Flood,
+    ];
+
+    pub fn letter(&self) -> char {
+        match self {
+            Snomask::Connect => 'c',
+            Snomask::Quit => 'q',
+            Snomask::Nick => 'n',
+            Snomask::Kill => 'k',
+            Snomask::Ban => 'x',
+            Snomask::Flood => 'f',
+        }
+    }
+
+    pub fn from_letter(letter: char) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|snomask| snomask.letter() == letter)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Snomask::Connect => "CONNECT",
+            Snomask::Quit => "QUIT",
+            Snomask::Nick => "NICK",
+            Snomask::Kill => "KILL",
+            Snomask::Ban => "BAN",
+            Snomask::Flood => "FLOOD",
+        }
+    }
+}
+
+impl fmt::Display for Snomask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.letter())
+    }
+}
+
+pub type SnomaskEvents = mpsc::Receiver<(Snomask, String)>;
+
+/// Passes the events carrying a snomask whatever the log level, so opers
+/// hear about them however quiet the log is.
+pub fn filter() -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+    filter_fn(|metadata| metadata.fields().field("snomask").is_some())
+}
+
+/// Picks the events carrying a snomask out of the log.
+#[derive(Debug)]
+pub struct SnomaskLayer {
+    events: mpsc::Sender<(Snomask, String)>,
+}
+
+/// The layer to add to the subscriber, behind its own `filter`, and the
+/// events it picks out for `deliver` to send on.
+pub fn layer() -> (SnomaskLayer, SnomaskEvents) {
+    let (events, receiver) = mpsc::channel(EVENT_QUEUE);
+    (SnomaskLayer { events }, receiver)
+}
+
+impl<S: Subscriber> Layer<S> for SnomaskLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = SnomaskVisitor::default();
+        event.record(&mut visitor);
+        if let (Some(snomask), Some(message)) = (visitor.snomask, visitor.message) {
+            //logging must never wait on opers, so a backlog sheds events
+            let _ = self.events.try_send((snomask, message));
+        }
+    }
+}
+
+#[derive(Default)]
+struct SnomaskVisitor {
+    snomask: Option<Snomask>,
+    message: Option<String>,
+}
+
+impl Visit for SnomaskVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        match field.name() {
+            "snomask" => {
+                let letter = format!("{:?}", value);
+                self.snomask = letter.chars().next().and_then(Snomask::from_letter);
+            }
+            "message" => self.message = Some(format!("{:?}", value)),
+            _ => {}
+        }
+    }
+}
+
+/// Sends each event as a server NOTICE to the opers subscribed to it.
+pub async fn deliver(server_state: SharedServerState, mut events: SnomaskEvents) {
+    while let Some((snomask, message)) = events.recv().await {
+        let handles = server_state
+            .users
+            .read()
+            .await
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        let text = format!("*** {}: {}", snomask.name(), message);
+        for handle in handles {
+            let client = handle.read().await;
+            if client.has_mode('s') && client.snomask.contains(&snomask) {
+                let nickname = client.nick.as_deref().unwrap_or("*");
+                let _ = client.sender.send(server_notice(nickname, &text));
+            }
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::collections::HashSet;
+
+use chrono::{SecondsFormat, Utc};
+use uuid::Uuid;
+
+use super::client::Capability;
+
+/// Splits the optional IRCv3 `@tags` prefix off a raw line, returning the
+/// parsed tags and the remainder of the line.
+pub fn split_tags(input: &str) -> (Vec<(String, String)>, &str) {
+    let Some(stripped) = input.strip_prefix('@') else {
+        return (vec![], input);
+    };
+
+    let (raw_tags, rest) = stripped.split_once(' ').unwrap_or((stripped, ""));
+    let tags = raw_tags
+        .split(';')
+        .filter(|tag| !tag.is_empty())
+        .map(|tag| match tag.split_once('=') {
+            Some((key, value)) => (key.to_string(), unescape_value(value)),
+            None => (tag.to_string(), String::new()),
+        })
+        .collect();
+
+    (tags, rest.trim_start())
+}
+
+pub fn escape_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn unescape_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(':') => unescaped.push(';'),
+            Some('s') => unescaped.push(' '),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => {}
+        }
+    }
+    unescaped
+}
+
+/// Tags attached by the server to an outgoing message. Each tag is only
+/// rendered for recipients that negotiated the capability it belongs to.
+#[derive(Debug, Clone)]
+pub struct MessageTags {
+    pub time: String,
+    pub msgid: Option<String>,
+    pub account: Option<String>,
+    pub client_tags: Vec<(String, String)>,
+}
+
+impl Default for MessageTags {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MessageTags {
+    pub fn new() -> Self {
+        Self {
+            time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            msgid: None,
+            account: None,
+            client_tags: vec![],
+        }
+    }
+
+    pub fn with_msgid(mut self) -> Self {
+        self.msgid = Some(Uuid::new_v4().to_string());
+        self
+    }
+
+    pub fn with_account(mut self, account: Option<String>) -> Self {
+        self.account = account;
+        self
+    }
+
+    pub fn with_client_tags(mut self, tags: &[(String, String)]) -> Self {
+        self.client_tags = tags
+            .iter()
+            .filter(|(key, _)| key.starts_with('+'))
+            .cloned()
+            .collect();
+        self
+    }
+
+    /// Renders the `@...` prefix (including the trailing space) for a
+    /// recipient with the given capabilities, or an empty string.
+    pub fn render(&self, capabilities: &HashSet<Capability>) -> String {
+        let mut tags = vec![];
+        if capabilities.contains(&Capability::ServerTime) {
+            tags.push(format!("time={}", self.time));
+        }
+        if capabilities.contains(&Capability::AccountTag) {
+            if let Some(account) = &self.account {
+                tags.push(format!("account={}", escape_value(account)));
+            }
+        }
+        if capabilities.contains(&Capability::MessageTags) {
+            if let Some(msgid) = &self.msgid {
+                tags.push(format!("msgid={}", msgid));
+            }
+            for (key, value) in &self.client_tags {
+                if value.is_empty() {
+                    tags.push(key.clone());
+                } else {
+                    tags.push(format!("{}={}", key, escape_value(value)));
+                }
+            }
+        }
+
+        if tags.is_empty() {
+            String::new()
+        } else {
+            format!("@{} ", tags.join(";"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn lines_without_tags_are_untouched() {
+        assert_eq!(split_tags("PRIVMSG #a :hi"), (vec![], "PRIVMSG #a :hi"));
+    }
+
+    #[test]
+    fn split_tags_parses_keys_and_values() {
+        let (tags, rest) = split_tags("@+draft/reply=abc;+flag;time=x  PRIVMSG #a :hi");
+        assert_eq!(
+            tags,
+            vec![
+                tag("+draft/reply", "abc"),
+                tag("+flag", ""),
+                tag("time", "x")
+            ]
+        );
+        assert_eq!(rest, "PRIVMSG #a :hi");
+    }
+
+    #[test]
+    fn split_tags_skips_empty_tags_and_handles_a_bare_prefix() {
+        let (tags, rest) = split_tags("@;;a=1; PING");
+        assert_eq!(tags, vec![tag("a", "1")]);
+        assert_eq!(rest, "PING");
+
+        let (tags, rest) = split_tags("@");
+        assert!(tags.is_empty());
+        assert_eq!(rest, "");
+    }
+
+    #[test]
+    fn split_tags_unescapes_values() {
+        let (tags, _) = split_tags("@+x=a\\sb\\:c\\\\d PING");
+        assert_eq!(tags, vec![tag("+x", "a b;c\\d")]);
+    }
+
+    #[test]
+    fn escape_value_escapes_every_special_character() {
+        assert_eq!(escape_value("a b;c\\d\r\n"), "a\\sb\\:c\\\\d\\r\\n");
+        assert_eq!(escape_value("plain"), "plain");
+    }
+
+    #[test]
+    fn unescape_value_reverses_escape_value() {
+        let value = "; \\ \r\n=ü";
+        assert_eq!(unescape_value(&escape_value(value)), value);
+    }
+
+    #[test]
+    fn unescape_value_drops_unknown_escapes_and_a_trailing_backslash() {
+        assert_eq!(unescape_value("\\b\\c"), "bc");
+        assert_eq!(unescape_value("abc\\"), "abc");
+        assert_eq!(unescape_value("\\"), "");
+    }
+
+    #[test]
+    fn client_tags_keep_only_client_only_keys() {
+        let tags = MessageTags::new().with_client_tags(&[tag("+a", "1"), tag("msgid", "forged")]);
+        assert_eq!(tags.client_tags, vec![tag("+a", "1")]);
+    }
+
+    #[test]
+    fn render_is_empty_without_capabilities() {
+        let tags = MessageTags::new().with_msgid();
+        assert_eq!(tags.render(&HashSet::new()), "");
+    }
+
+    #[test]
+    fn render_includes_only_negotiated_tags() {
+        let mut tags = MessageTags::new()
+            .with_account(Some("al ice".to_string()))
+            .with_client_tags(&[tag("+a", "x;y"), tag("+b", "")]);
+        tags.time = "T".to_string();
+        tags.msgid = Some("M".to_string());
+
+        let capabilities = HashSet::from([Capability::ServerTime]);
+        assert_eq!(tags.render(&capabilities), "@time=T ");
+
+        let capabilities = HashSet::from([
+            Capability::ServerTime,
+            Capability::AccountTag,
+            Capability::MessageTags,
+        ]);
+        assert_eq!(
+            tags.render(&capabilities),
+            "@time=T;account=al\\sice;msgid=M;+a=x\\:y;+b "
+        );
+    }
+}
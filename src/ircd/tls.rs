@@ -0,0 +1,142 @@
+use std::fs::File;
+use std::io::{BufReader, Error, ErrorKind};
+use std::sync::Arc;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{DigitallySignedStruct, DistinguishedName, SignatureScheme};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+use crate::configuration::TlsConfig;
+
+/// Hands the current certificate to every new handshake. Reloading swaps
+/// the certificate in place, so established connections are left alone.
+#[derive(Debug)]
+pub struct CertificateStore {
+    current: std::sync::RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateStore {
+    pub fn load(config: &TlsConfig) -> std::io::Result<Self> {
+        Ok(Self {
+            current: std::sync::RwLock::new(Arc::new(Self::read(config)?)),
+        })
+    }
+
+    /// Reads the certificate and key named by `config` from disk.
+    pub fn read(config: &TlsConfig) -> std::io::Result<CertifiedKey> {
+        read_certified_key(&config.cert, &config.key)
+    }
+
+    /// Serves `certified_key` to handshakes from now on.
+    pub fn replace(&self, certified_key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(certified_key);
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Asks for a client certificate without requiring one. Certificates are
+/// not checked against any CA; clients are identified by fingerprint only,
+/// so all that is verified is that the client holds the private key.
+#[derive(Debug)]
+struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn offer_client_auth(&self) -> bool {
+        true
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+pub fn acceptor(store: Arc<CertificateStore>) -> std::io::Result<TlsAcceptor> {
+    let provider = Arc::new(default_provider());
+    let verifier = Arc::new(AnyClientCert {
+        algorithms: provider.signature_verification_algorithms,
+    });
+    let config = rustls::ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| Error::new(ErrorKind::InvalidInput, e))?
+        .with_client_cert_verifier(verifier)
+        .with_cert_resolver(store);
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// SHA-256 fingerprint of a certificate as lowercase hex.
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    Sha256::digest(cert.as_ref())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn read_certified_key(cert_path: &str, key_path: &str) -> std::io::Result<CertifiedKey> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    if certs.is_empty() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("no certificates found in {}", cert_path),
+        ));
+    }
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?.ok_or_else(
+        || {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("no private key found in {}", key_path),
+            )
+        },
+    )?;
+    let key = any_supported_type(&key).map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    Ok(CertifiedKey::new(certs, key))
+}
@@ -0,0 +1,300 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_tungstenite::tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tokio_tungstenite::tungstenite::http::{HeaderValue, StatusCode};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use super::tls;
+use crate::helpers::mask_matches;
+
+const TEXT_PROTOCOL: &str = "text.ircv3.net";
+const BINARY_PROTOCOL: &str = "binary.ircv3.net";
+
+/// A client socket. Plaintext connections can be upgraded in place by
+/// STARTTLS, so the stream type has to be decided at runtime.
+#[derive(Debug)]
+pub enum ClientStream {
+    Plain(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream<ClientStream>>),
+}
+
+impl ClientStream {
+    /// Performs the server side of a TLS handshake on a plaintext stream,
+    /// returning the upgraded stream and the client certificate fingerprint.
+    pub async fn upgrade(self, acceptor: &TlsAcceptor) -> io::Result<(Self, Option<String>)> {
+        if let ClientStream::Tls(_) = self {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "connection is already using TLS",
+            ));
+        }
+        let stream = acceptor.accept(self).await?;
+        let certfp = stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .map(tls::fingerprint);
+        Ok((ClientStream::Tls(Box::new(stream)), certfp))
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Where a client's lines come from and go to: a byte stream carrying
+/// CRLF-terminated lines, or a WebSocket carrying one message per frame.
+#[derive(Debug)]
+pub enum Transport {
+    Stream {
+        reader: BufReader<ClientStream>,
+        /// Start of a line whose end has not arrived yet
+        partial: Vec<u8>,
+        max_line: usize,
+    },
+    WebSocket {
+        socket: Box<WebSocketStream<ClientStream>>,
+        binary: bool,
+    },
+}
+
+impl Transport {
+    /// Lines longer than `max_line` bytes are refused as they arrive, so a
+    /// client cannot make the server buffer an endless line.
+    pub fn stream(stream: ClientStream, max_line: usize) -> Self {
+        Transport::Stream {
+            reader: BufReader::new(stream),
+            partial: Vec::new(),
+            max_line,
+        }
+    }
+
+    /// Completes the WebSocket handshake, negotiating one of the IRCv3
+    /// subprotocols. Browsers always send `Origin`, so when an allow-list is
+    /// configured a request carrying any other origin is refused; clients
+    /// that send none are not browsers and are let through.
+    pub async fn websocket(
+        stream: ClientStream,
+        allowed_origins: &[String],
+        max_line: usize,
+    ) -> io::Result<Self> {
+        let mut binary = false;
+        //the error type is fixed by tungstenite's callback signature
+        #[allow(clippy::result_large_err)]
+        let callback = |request: &Request, mut response: Response| {
+            let origin = request
+                .headers()
+                .get("Origin")
+                .and_then(|origin| origin.to_str().ok());
+            if let Some(origin) = origin.filter(|_| !allowed_origins.is_empty()) {
+                if !allowed_origins
+                    .iter()
+                    .any(|allowed| mask_matches(allowed, origin))
+                {
+                    let mut error = ErrorResponse::new(Some("Origin not allowed".to_string()));
+                    *error.status_mut() = StatusCode::FORBIDDEN;
+                    return Err(error);
+                }
+            }
+
+            let offered = request
+                .headers()
+                .get_all("Sec-WebSocket-Protocol")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .collect::<Vec<_>>();
+            let protocol = [BINARY_PROTOCOL, TEXT_PROTOCOL]
+                .into_iter()
+                .find(|protocol| offered.contains(protocol));
+            if let Some(protocol) = protocol {
+                binary = protocol == BINARY_PROTOCOL;
+                response
+                    .headers_mut()
+                    .insert("Sec-WebSocket-Protocol", HeaderValue::from_static(protocol));
+            }
+            Ok(response)
+        };
+        let config = WebSocketConfig {
+            max_message_size: Some(max_line),
+            max_frame_size: Some(max_line),
+            ..Default::default()
+        };
+        let socket =
+            tokio_tungstenite::accept_hdr_async_with_config(stream, callback, Some(config))
+                .await
+                .map_err(io::Error::other)?;
+        Ok(Transport::WebSocket {
+            socket: Box::new(socket),
+            binary,
+        })
+    }
+
+    /// Reads the next message without its line ending. Cancel safe, so it
+    /// can be raced against outgoing messages. A message over the length
+    /// limit fails with `ErrorKind::InvalidData`.
+    pub async fn read_line(&mut self) -> io::Result<Option<String>> {
+        match self {
+            Transport::Stream {
+                reader,
+                partial,
+                max_line,
+            } => loop {
+                //only the wait for more input can be cancelled, so nothing
+                //read is lost when another branch wins
+                let available = reader.fill_buf().await?;
+                if available.is_empty() {
+                    if partial.is_empty() {
+                        return Ok(None);
+                    }
+                    return Ok(Some(take_line(partial)));
+                }
+                let (used, complete) = match available.iter().position(|&byte| byte == b'\n') {
+                    Some(end) => (end + 1, true),
+                    None => (available.len(), false),
+                };
+                partial.extend_from_slice(&available[..used]);
+                reader.consume(used);
+                if partial.len() > *max_line {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+                }
+                if complete {
+                    return Ok(Some(take_line(partial)));
+                }
+            },
+            Transport::WebSocket { socket, .. } => loop {
+                let line = match socket.next().await {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Binary(data))) => String::from_utf8_lossy(&data).into_owned(),
+                    Some(Ok(Message::Close(_))) | None => return Ok(None),
+                    Some(Ok(_)) => continue,
+                    Some(Err(tungstenite::Error::Capacity(e))) => {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+                    }
+                    Some(Err(e)) => return Err(io::Error::other(e)),
+                };
+                return Ok(Some(line.trim_end_matches(['\r', '\n']).to_string()));
+            },
+        }
+    }
+
+    /// Changes the length limit of stream lines; WebSocket connections keep
+    /// the one set during their handshake.
+    pub fn set_max_line(&mut self, limit: usize) {
+        if let Transport::Stream { max_line, .. } = self {
+            *max_line = limit;
+        }
+    }
+
+    /// Sends a queued message, which may hold several CRLF-terminated lines.
+    pub async fn write_line(&mut self, message: &str) -> io::Result<()> {
+        match self {
+            Transport::Stream { reader, .. } => {
+                let stream = reader.get_mut();
+                stream.write_all(message.as_bytes()).await?;
+                //TLS buffers records until flushed
+                stream.flush().await
+            }
+            Transport::WebSocket { socket, binary } => {
+                for line in message.split("\r\n").filter(|line| !line.is_empty()) {
+                    let frame = if *binary {
+                        Message::Binary(line.as_bytes().to_vec())
+                    } else {
+                        Message::Text(line.to_string())
+                    };
+                    socket.send(frame).await.map_err(io::Error::other)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether input has been read from the socket but not yet consumed.
+    pub fn has_buffered_input(&mut self) -> bool {
+        match self {
+            Transport::Stream {
+                reader, partial, ..
+            } => !reader.buffer().is_empty() || !partial.is_empty(),
+            Transport::WebSocket { .. } => false,
+        }
+    }
+
+    pub fn supports_starttls(&self) -> bool {
+        matches!(self, Transport::Stream { .. })
+    }
+
+    pub async fn upgrade(self, acceptor: &TlsAcceptor) -> io::Result<(Self, Option<String>)> {
+        let Transport::Stream {
+            reader, max_line, ..
+        } = self
+        else {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "STARTTLS is not available over WebSocket",
+            ));
+        };
+        let (stream, certfp) = reader.into_inner().upgrade(acceptor).await?;
+        Ok((Transport::stream(stream, max_line), certfp))
+    }
+}
+
+/// Empties `partial` into a line, dropping the CRLF or LF ending.
+fn take_line(partial: &mut Vec<u8>) -> String {
+    let line = String::from_utf8_lossy(partial)
+        .trim_end_matches(['\r', '\n'])
+        .to_string();
+    partial.clear();
+    line
+}
@@ -1,2 +1,3 @@
+pub mod configuration;
 pub mod helpers;
 pub mod ircd;
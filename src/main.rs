@@ -1,17 +1,44 @@
+use oxide_ircd::configuration::get_configuration;
 use oxide_ircd::helpers::{get_subscriber, init_subscriber};
 use oxide_ircd::ircd::ircd::run;
-use tokio::net::TcpListener;
+use oxide_ircd::ircd::listener::Listener;
+use oxide_ircd::ircd::snomask;
+use tracing_subscriber::layer::{Layer, SubscriberExt};
 
 #[tokio::main]
 async fn main() {
-    let subscriber = get_subscriber("oxide_ircd".into(), "debug".into(), std::io::stdout);
+    //read before logging starts, since it sets the log level
+    let configuration = get_configuration().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    let (snomasks, snomask_events) = snomask::layer();
+    let subscriber = get_subscriber(
+        "oxide_ircd".into(),
+        configuration.log_level.clone(),
+        std::io::stdout,
+    )
+    .with(snomasks.with_filter(snomask::filter()));
     init_subscriber(subscriber);
 
-    let listener = TcpListener::bind("127.0.0.1:6667")
-        .await
-        .expect("Failed to bind to port 23");
+    let mut listeners = vec![];
+    for (i, listener_config) in configuration.listeners.iter().enumerate() {
+        match Listener::bind(listener_config.clone()).await {
+            Ok(listener) => listeners.push(listener),
+            Err(e) => {
+                tracing::error!(
+                    "listeners[{}]: failed to bind to {}: {}",
+                    i,
+                    listener_config.name(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
 
-    if let Err(e) = run(listener).await {
+    if let Err(e) = run(listeners, configuration, snomask_events).await {
         tracing::error!("Application error: {}", e);
         std::process::exit(1);
     }